@@ -1,7 +1,7 @@
 //! Possible ZIP compression methods.
 
 /// Compression methods for the contents of a ZIP file.
-#[deriving(FromPrimitive, Clone)]
+#[deriving(FromPrimitive, Clone, PartialEq, Show)]
 pub enum CompressionMethod
 {
     /// The file is stored (no compression)
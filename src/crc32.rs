@@ -61,12 +61,84 @@ pub fn update(prev: u32, buf: &[u8]) -> u32
     return crc ^ !0u32;
 }
 
+/// Update a checksum with one byte, without the inversion before and after that `update` applies.
+///
+/// This is the form used by the key schedule of the traditional PKWARE encryption.
+pub fn update_raw_byte(crc: u32, byte: u8) -> u32
+{
+    CRC32_TABLE[((crc ^ (byte as u32)) & 0xFF) as uint] ^ (crc >> 8)
+}
+
+fn gf2_matrix_times(mat: &[u32, ..32], mut vec: u32) -> u32
+{
+    let mut sum = 0u32;
+    let mut i = 0u;
+    while vec != 0
+    {
+        if vec & 1 == 1 { sum ^= mat[i]; }
+        vec >>= 1;
+        i += 1;
+    }
+    sum
+}
+
+fn gf2_matrix_square(square: &mut [u32, ..32], mat: &[u32, ..32])
+{
+    for n in range(0u, 32)
+    {
+        square[n] = gf2_matrix_times(mat, mat[n]);
+    }
+}
+
+/// Combine the checksums of two consecutive blocks of data.
+///
+/// Given crc1 of a first block and crc2 of a second block of len2 bytes, this returns the checksum
+/// of both blocks concatenated, without needing the data itself.
+pub fn combine(crc1: u32, crc2: u32, mut len2: u64) -> u32
+{
+    if len2 == 0 { return crc1 }
+
+    let mut even = [0u32, ..32];
+    let mut odd = [0u32, ..32];
+
+    // Operator for one zero bit in odd
+    odd[0] = 0xedb88320;
+    let mut row = 1u32;
+    for n in range(1u, 32)
+    {
+        odd[n] = row;
+        row <<= 1;
+    }
+
+    // Operators for two and four zero bits
+    gf2_matrix_square(&mut even, &odd);
+    gf2_matrix_square(&mut odd, &even);
+
+    // Apply len2 zeros to crc1, the first square puts the operator for one zero byte in even
+    let mut crc1 = crc1;
+    loop
+    {
+        gf2_matrix_square(&mut even, &odd);
+        if len2 & 1 == 1 { crc1 = gf2_matrix_times(&even, crc1); }
+        len2 >>= 1;
+        if len2 == 0 { break }
+
+        gf2_matrix_square(&mut odd, &even);
+        if len2 & 1 == 1 { crc1 = gf2_matrix_times(&odd, crc1); }
+        len2 >>= 1;
+        if len2 == 0 { break }
+    }
+
+    crc1 ^ crc2
+}
+
 /// Reader that validates the CRC32 when it reaches the EOF.
 pub struct Crc32Reader<R>
 {
     inner: R,
     crc: u32,
     check: u32,
+    finished: bool,
 }
 
 impl<R: Reader> Crc32Reader<R>
@@ -79,6 +151,7 @@ impl<R: Reader> Crc32Reader<R>
             inner: inner,
             crc: 0,
             check: checksum,
+            finished: false,
         }
     }
 
@@ -86,6 +159,16 @@ impl<R: Reader> Crc32Reader<R>
     {
         self.check == self.crc
     }
+
+    /// Whether the data matched the checksum, or None if the end has not been reached yet.
+    pub fn crc_ok(&self) -> Option<bool>
+    {
+        match self.finished
+        {
+            true => Some(self.check_matches()),
+            false => None,
+        }
+    }
 }
 
 impl<R: Reader> Reader for Crc32Reader<R>
@@ -97,6 +180,7 @@ impl<R: Reader> Reader for Crc32Reader<R>
             Ok(n) => n,
             Err(ref e) if e.kind == io::EndOfFile =>
             {
+                self.finished = true;
                 return
                 if self.check_matches() { Err(e.clone()) }
                 else { Err(io::IoError { kind: io::OtherIoError, desc: "Invalid checksum", detail: None, }) }
@@ -107,3 +191,26 @@ impl<R: Reader> Reader for Crc32Reader<R>
         Ok(count)
     }
 }
+
+#[cfg(test)]
+mod test
+{
+    #[test]
+    fn combine_matches_concatenation()
+    {
+        let a = b"The quick brown fox ";
+        let b = b"jumps over the lazy dog";
+        let mut whole = a.to_vec();
+        whole.push_all(b);
+
+        let combined = super::combine(super::update(0, a), super::update(0, b), b.len() as u64);
+        assert_eq!(combined, super::update(0, whole.as_slice()));
+    }
+
+    #[test]
+    fn combine_with_empty_second_part()
+    {
+        let a = b"Hello, World!";
+        assert_eq!(super::combine(super::update(0, a), 0, 0), 0xec4ac3d0);
+    }
+}
@@ -0,0 +1,453 @@
+//! Extraction of ZIP files to the filesystem.
+
+use std::ascii::AsciiExt;
+use std::collections::HashSet;
+use std::io;
+use std::io::{IoResult, IoError};
+use std::io::fs;
+use reader::ZipReader;
+use types::ZipFile;
+
+/// What to do with files of which the paths only differ in case, such as `README` and `readme`.
+///
+/// On case-insensitive file systems such files would overwrite each other. Only ASCII letters are
+/// compared case-insensitively.
+#[deriving(Clone, PartialEq, Show)]
+pub enum CaseCollisionPolicy
+{
+    /// Fail the extraction
+    ErrorOnCollision,
+    /// Extract both, so the last one wins on a case-insensitive file system
+    OverwriteOnCollision,
+    /// Add a number to the name of the later file
+    RenameOnCollision,
+}
+
+/// Options controlling how a ZIP file is extracted.
+pub struct ExtractOptions
+{
+    allow_unsafe_symlinks: bool,
+    symlink_as_file: bool,
+    case_collision: CaseCollisionPolicy,
+    skip_macos_metadata: bool,
+    backslash_as_separator: bool,
+}
+
+impl ExtractOptions
+{
+    /// Options with the default, safe, behaviour.
+    pub fn new() -> ExtractOptions
+    {
+        ExtractOptions
+        {
+            allow_unsafe_symlinks: false,
+            symlink_as_file: false,
+            case_collision: OverwriteOnCollision,
+            skip_macos_metadata: false,
+            backslash_as_separator: false,
+        }
+    }
+
+    /// Allow symlinks with an absolute target or a target containing `..` (disabled by default).
+    ///
+    /// Such links can point outside of the destination directory, and a later file written through
+    /// the link would then escape it as well.
+    pub fn allow_unsafe_symlinks(&mut self, allow: bool) -> &mut ExtractOptions
+    {
+        self.allow_unsafe_symlinks = allow;
+        self
+    }
+
+    /// Write symlinks as regular files containing the link target (disabled by default).
+    ///
+    /// Use this on platforms or file systems without symlink support. The target is not checked,
+    /// as no link is created.
+    pub fn symlink_as_file(&mut self, as_file: bool) -> &mut ExtractOptions
+    {
+        self.symlink_as_file = as_file;
+        self
+    }
+
+    /// Choose how files with paths that only differ in case are handled (`OverwriteOnCollision` by default).
+    pub fn case_collision(&mut self, policy: CaseCollisionPolicy) -> &mut ExtractOptions
+    {
+        self.case_collision = policy;
+        self
+    }
+
+    /// Skip files added by macOS, such as `__MACOSX/` entries and `.DS_Store` files (disabled by default).
+    pub fn skip_macos_metadata(&mut self, skip: bool) -> &mut ExtractOptions
+    {
+        self.skip_macos_metadata = skip;
+        self
+    }
+
+    /// Treat backslashes in names as path separators (disabled by default).
+    ///
+    /// The ZIP specification only allows forward slashes, but some Windows tools write backslashes.
+    /// The resulting paths are sanitized as usual.
+    pub fn treat_backslash_as_separator(&mut self, treat: bool) -> &mut ExtractOptions
+    {
+        self.backslash_as_separator = treat;
+        self
+    }
+}
+
+impl<T: Reader+Seek> ZipReader<T>
+{
+    /// Extract all contained files into the directory `dest`, using the default options.
+    ///
+    /// Paths are sanitized, so no file is written outside of `dest`. The last modified times of the
+    /// files and directories are restored. Directories are handled last, as writing files inside a
+    /// directory updates its time.
+    pub fn extract(&self, dest: &Path) -> IoResult<()>
+    {
+        self.extract_with_options(dest, &ExtractOptions::new())
+    }
+
+    /// Extract all contained files into the directory `dest`.
+    pub fn extract_with_options(&self, dest: &Path, options: &ExtractOptions) -> IoResult<()>
+    {
+        self.extract_mapped(dest, options, |file| Some(file_path(file)))
+    }
+
+    /// Extract the contained files into the directory `dest`, choosing their paths with `f`.
+    ///
+    /// `f` returns the path relative to `dest`, or None to skip the file. The returned paths are
+    /// sanitized like the names in the ZIP file, so no file is written outside of `dest`.
+    pub fn extract_with(&self, dest: &Path, f: |&ZipFile| -> Option<Path>) -> IoResult<()>
+    {
+        self.extract_mapped(dest, &ExtractOptions::new(), f)
+    }
+
+    fn extract_mapped(&self, dest: &Path, options: &ExtractOptions, f: |&ZipFile| -> Option<Path>) -> IoResult<()>
+    {
+        let mut directories = Vec::new();
+        let mut extracted = HashSet::new();
+
+        for file in self.files()
+        {
+            if options.skip_macos_metadata && file.is_macos_metadata() { continue }
+
+            let is_directory = match options.backslash_as_separator
+            {
+                true => file.file_name.replace("\\", "/").as_slice().ends_with("/"),
+                false => file.file_name.as_slice().ends_with("/"),
+            };
+            let mut relative = match f(file)
+            {
+                Some(ref path) if options.backslash_as_separator => sanitize_path(&backslashes_to_slashes(path)),
+                Some(path) => sanitize_path(&path),
+                None => continue,
+            };
+            // An empty name is valid, but cannot be extracted without overwriting dest itself
+            if relative.as_vec() == b"" || relative.as_vec() == b"."
+            {
+                warn!("Skipping file with an empty name");
+                continue;
+            }
+            if !is_directory
+            {
+                relative = try!(resolve_case_collision(&mut extracted, relative, options.case_collision));
+            }
+            let outpath = dest.join(relative);
+
+            if is_directory
+            {
+                try!(fs::mkdir_recursive(&outpath, io::USER_DIR));
+                directories.push((outpath, file));
+            }
+            else if file.is_symlink() && !options.symlink_as_file
+            {
+                try!(fs::mkdir_recursive(&outpath.dir_path(), io::USER_DIR));
+                try!(self.extract_symlink(file, &outpath, options));
+            }
+            else
+            {
+                try!(fs::mkdir_recursive(&outpath.dir_path(), io::USER_DIR));
+                let mut outfile = try!(io::File::create(&outpath));
+                try!(self.extract_entry_to(file, &mut outfile));
+                try!(set_modified_time(&outpath, file));
+            }
+        }
+
+        for &(ref path, file) in directories.iter()
+        {
+            try!(set_modified_time(path, file));
+        }
+
+        Ok(())
+    }
+
+    fn extract_symlink(&self, file: &ZipFile, outpath: &Path, options: &ExtractOptions) -> IoResult<()>
+    {
+        let mut target = io::MemWriter::new();
+        try!(self.extract_entry_to(file, &mut target));
+        let target = match Path::new_opt(target.unwrap())
+        {
+            Some(target) => target,
+            None => return Err(IoError
+                               {
+                                   kind: io::InvalidInput,
+                                   desc: "Symlink target contains a null byte",
+                                   detail: Some(file.file_name.clone()),
+                               }),
+        };
+
+        if !options.allow_unsafe_symlinks && !is_safe_symlink_target(&target)
+        {
+            return Err(IoError
+                       {
+                           kind: io::OtherIoError,
+                           desc: "Refusing to create a symlink pointing outside of the destination",
+                           detail: Some(format!("{} -> {}", file.file_name, target.display())),
+                       })
+        }
+
+        fs::symlink(&target, outpath)
+    }
+}
+
+/// Apply the policy to a path that is about to be extracted, recording the path that is used.
+fn resolve_case_collision(extracted: &mut HashSet<String>, path: Path, policy: CaseCollisionPolicy) -> IoResult<Path>
+{
+    let name = String::from_utf8_lossy(path.as_vec()).into_string();
+    if extracted.insert(name.as_slice().to_ascii_lower()) { return Ok(path) }
+
+    match policy
+    {
+        OverwriteOnCollision => Ok(path),
+        ErrorOnCollision => Err(IoError
+                                {
+                                    kind: io::PathAlreadyExists,
+                                    desc: "Another file with the same path in a different case was extracted",
+                                    detail: Some(name),
+                                }),
+        RenameOnCollision =>
+        {
+            let mut n = 1u;
+            loop
+            {
+                let candidate = ::util::numbered_name(name.as_slice(), n);
+                if extracted.insert(candidate.as_slice().to_ascii_lower()) { return Ok(Path::new(candidate)) }
+                n += 1;
+            }
+        },
+    }
+}
+
+fn is_safe_symlink_target(target: &Path) -> bool
+{
+    !target.is_absolute() && !target.components().any(|component| component == b"..")
+}
+
+fn set_modified_time(path: &Path, file: &ZipFile) -> IoResult<()>
+{
+    let seconds = ::std::cmp::max(0, file.modified_unix_time()) as u64;
+    fs::change_file_times(path, seconds * 1000, seconds * 1000)
+}
+
+/// Path of a file as stored in the ZIP, cut off at a null byte.
+fn file_path(file: &ZipFile) -> Path
+{
+    let filename = file.file_name.as_slice();
+    let no_null_filename = match filename.find('\0') {
+        Some(index) => filename.slice_to(index),
+        None => filename,
+    };
+    Path::new(no_null_filename)
+}
+
+fn backslashes_to_slashes(path: &Path) -> Path
+{
+    let bytes: Vec<u8> = path.as_vec().iter().map(|&b| if b == b'\\' { b'/' } else { b }).collect();
+    Path::new(bytes)
+}
+
+/// Make a path relative and remove leading `..` components, so it stays inside the destination.
+fn sanitize_path(path: &Path) -> Path
+{
+    path.components()
+        .skip_while(|component| *component == b"..")
+        .fold(Path::new(""), |mut p, cur| {
+            p.push(cur);
+            p
+        })
+}
+
+#[cfg(test)]
+mod test
+{
+    use std::io;
+    use std::io::fs;
+    use time;
+    use compression;
+    use testutil;
+    use writer::FileOptions;
+
+    #[test]
+    fn directory_gets_recorded_time()
+    {
+        let zip = testutil::open_zip(testutil::write_zip(|zip|
+        {
+            zip.set_reproducible(time::strptime("2010-05-06 07:08:10", "%Y-%m-%d %H:%M:%S").unwrap());
+            try!(zip.add_directory("dir"));
+            try!(zip.start_file("dir/a.txt", compression::Stored));
+            zip.write(b"a")
+        }));
+        let dest = io::TempDir::new("zip-extract").unwrap();
+        zip.extract(dest.path()).unwrap();
+
+        let expected = zip.by_name("dir/").unwrap().modified_unix_time() as u64 * 1000;
+        assert_eq!(fs::stat(&dest.path().join("dir")).unwrap().modified, expected);
+    }
+
+    fn symlink_zip(target: &[u8]) -> Vec<u8>
+    {
+        testutil::write_zip(|zip|
+        {
+            let mut options = FileOptions::new(compression::Stored);
+            options.unix_mode = Some(0o120777);
+            try!(zip.start_file_with_options("link", options));
+            zip.write(target)
+        })
+    }
+
+    #[test]
+    fn absolute_symlink_is_refused()
+    {
+        let zip = testutil::open_zip(symlink_zip(b"/etc/passwd"));
+        let dest = io::TempDir::new("zip-extract").unwrap();
+
+        assert!(zip.extract(dest.path()).is_err());
+        assert!(fs::lstat(&dest.path().join("link")).is_err());
+    }
+
+    #[test]
+    fn symlink_target_with_null_byte_is_refused()
+    {
+        let zip = testutil::open_zip(symlink_zip(b"target\x00"));
+        let dest = io::TempDir::new("zip-extract").unwrap();
+
+        let error = zip.extract(dest.path()).unwrap_err();
+        assert_eq!(error.kind, io::InvalidInput);
+    }
+
+    #[test]
+    fn file_with_empty_name_is_found_and_skipped()
+    {
+        let zip = testutil::open_zip(testutil::stored_zip(&[("", b"nameless"), ("a.txt", b"a")]));
+        assert_eq!(zip.read_file_to_vec("").unwrap().as_slice(), b"nameless");
+
+        let dest = io::TempDir::new("zip-extract").unwrap();
+        zip.extract(dest.path()).unwrap();
+        assert_eq!(io::File::open(&dest.path().join("a.txt")).read_to_end().unwrap().as_slice(), b"a");
+    }
+
+    #[test]
+    fn symlink_is_extracted_as_file_with_target()
+    {
+        // Unsafe targets are fine, as no link is created
+        let zip = testutil::open_zip(symlink_zip(b"../outside"));
+        let dest = io::TempDir::new("zip-extract").unwrap();
+        zip.extract_with_options(dest.path(), super::ExtractOptions::new().symlink_as_file(true)).unwrap();
+
+        let path = dest.path().join("link");
+        assert_eq!(fs::lstat(&path).unwrap().kind, io::TypeFile);
+        assert_eq!(io::File::open(&path).read_to_end().unwrap().as_slice(), b"../outside");
+    }
+
+    #[test]
+    fn extract_with_filters_and_renames()
+    {
+        let zip = testutil::open_zip(testutil::stored_zip(&[("src/a.rs", b"a"), ("docs/b.md", b"b"), ("src/c.rs", b"c")]));
+        let dest = io::TempDir::new("zip-extract").unwrap();
+        zip.extract_with(dest.path(), |file|
+        {
+            let name = file.file_name.as_slice();
+            if name.starts_with("src/") { Some(Path::new(format!("../code/{}", name.slice_from(4)))) } else { None }
+        }).unwrap();
+
+        // The returned paths are sanitized, so the leading .. is dropped
+        assert_eq!(io::File::open(&dest.path().join("code/a.rs")).read_to_end().unwrap().as_slice(), b"a");
+        assert_eq!(io::File::open(&dest.path().join("code/c.rs")).read_to_end().unwrap().as_slice(), b"c");
+        assert!(!dest.path().join("docs").exists());
+        assert!(!dest.path().join("src").exists());
+    }
+
+    #[test]
+    fn case_collisions_follow_policy()
+    {
+        let zip = testutil::open_zip(testutil::stored_zip(&[("README.txt", b"upper"), ("readme.txt", b"lower")]));
+        let extract = |policy|
+        {
+            let dest = io::TempDir::new("zip-extract").unwrap();
+            let result = zip.extract_with_options(dest.path(), super::ExtractOptions::new().case_collision(policy));
+            (dest, result)
+        };
+        let contents = |path: Path| io::File::open(&path).read_to_end().unwrap();
+
+        let (dest, result) = extract(super::ErrorOnCollision);
+        assert_eq!(result.unwrap_err().kind, io::PathAlreadyExists);
+        assert_eq!(contents(dest.path().join("README.txt")).as_slice(), b"upper");
+        assert!(!dest.path().join("readme.txt").exists());
+
+        // On a case-sensitive file system both files exist
+        let (dest, result) = extract(super::OverwriteOnCollision);
+        result.unwrap();
+        assert_eq!(contents(dest.path().join("readme.txt")).as_slice(), b"lower");
+
+        let (dest, result) = extract(super::RenameOnCollision);
+        result.unwrap();
+        assert_eq!(contents(dest.path().join("README.txt")).as_slice(), b"upper");
+        assert_eq!(contents(dest.path().join("readme (1).txt")).as_slice(), b"lower");
+        assert!(!dest.path().join("readme.txt").exists());
+    }
+
+    #[test]
+    fn backslashes_are_separators_when_enabled()
+    {
+        let zip = testutil::open_zip(testutil::stored_zip(&[("dir\\file.txt", b"contents"), ("..\\escape.txt", b"e")]));
+
+        let dest = io::TempDir::new("zip-extract").unwrap();
+        zip.extract(dest.path()).unwrap();
+        assert!(dest.path().join("dir\\file.txt").exists());
+        assert!(!dest.path().join("dir").is_dir());
+
+        let dest = io::TempDir::new("zip-extract").unwrap();
+        zip.extract_with_options(dest.path(), super::ExtractOptions::new().treat_backslash_as_separator(true)).unwrap();
+        assert_eq!(io::File::open(&dest.path().join("dir").join("file.txt")).read_to_end().unwrap().as_slice(), b"contents");
+        // Sanitized like a name with slashes
+        assert!(dest.path().join("escape.txt").exists());
+    }
+
+    #[test]
+    fn macos_metadata_is_not_extracted_when_skipped()
+    {
+        let zip = testutil::open_zip(testutil::stored_zip(&[("foo", b"data"), ("__MACOSX/._foo", b"fork"), (".DS_Store", b"store")]));
+
+        let dest = io::TempDir::new("zip-extract").unwrap();
+        zip.extract_with_options(dest.path(), super::ExtractOptions::new().skip_macos_metadata(true)).unwrap();
+        assert_eq!(io::File::open(&dest.path().join("foo")).read_to_end().unwrap().as_slice(), b"data");
+        assert!(!dest.path().join("__MACOSX").exists());
+        assert!(!dest.path().join(".DS_Store").exists());
+
+        // Without the option the metadata is extracted like any other file
+        let dest = io::TempDir::new("zip-extract").unwrap();
+        zip.extract(dest.path()).unwrap();
+        assert!(dest.path().join("__MACOSX").join("._foo").exists());
+        assert!(dest.path().join(".DS_Store").exists());
+    }
+
+    #[test]
+    fn directory_with_trailing_backslash_is_created_when_enabled()
+    {
+        let zip = testutil::open_zip(testutil::stored_zip(&[("dir\\", b""), ("dir\\sub\\", b""), ("dir\\a.txt", b"a")]));
+        let dest = io::TempDir::new("zip-extract").unwrap();
+        zip.extract_with_options(dest.path(), super::ExtractOptions::new().treat_backslash_as_separator(true)).unwrap();
+
+        assert!(dest.path().join("dir").is_dir());
+        assert!(dest.path().join("dir").join("sub").is_dir());
+        assert_eq!(io::File::open(&dest.path().join("dir").join("a.txt")).read_to_end().unwrap().as_slice(), b"a");
+    }
+}
@@ -8,17 +8,23 @@
 extern crate time;
 extern crate flate2;
 
-pub use reader::ZipReader;
-pub use writer::ZipWriter;
-pub use types::ZipFile;
+pub use reader::{ZipReader, ZipRawReader, ZipFileReader, RawEntries, FilesExcludingMacos};
+pub use writer::{ZipWriter, FileOptions, Zip64Mode, Zip64Auto, Zip64Always, Zip64Never, ConflictPolicy, SkipDuplicate, OverwriteDuplicate, RenameDuplicate, SpecialFilePolicy, SkipSpecialFiles, ErrorOnSpecialFiles, merge_archives};
+pub use types::{ZipFile, ArchiveInfo, DataRange, HostSystem, HostDos, HostUnix, HostNtfs};
+pub use extract::{ExtractOptions, CaseCollisionPolicy, ErrorOnCollision, OverwriteOnCollision, RenameOnCollision};
+pub use util::{DryRunSink, SharedMemReader, tm_to_msdos_datetime};
 
 mod util;
-mod spec;
-mod reader_spec;
+pub mod spec;
+pub mod reader_spec;
 mod writer_spec;
-mod crc32;
+pub mod crc32;
 mod reader;
 mod types;
 pub mod compression;
 mod writer;
+mod extract;
 mod cp437;
+mod zipcrypto;
+#[cfg(test)]
+mod testutil;
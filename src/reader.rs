@@ -1,12 +1,13 @@
 use crc32::Crc32Reader;
-use types::ZipFile;
+use types::{ZipFile, ArchiveInfo};
 use compression;
 use spec;
 use reader_spec;
 use std::io;
 use std::io::{IoResult, IoError};
-use std::cell::RefCell;
+use std::cell::{Ref, RefCell, RefMut};
 use flate2::FlateReader;
+use flate2::reader::DeflateDecoder;
 
 /// Wrapper for reading the contents of a ZIP file.
 ///
@@ -36,6 +37,113 @@ pub struct ZipReader<T>
 {
     inner: RefCell<T>,
     files: Vec<ZipFile>,
+    count_mismatch: bool,
+    archive_offset: u64,
+    max_uncompressed_size: Option<u64>,
+    directory_start: u64,
+    directory_size: u64,
+    limits: reader_spec::HeaderLimits,
+}
+
+/// Reader for the raw, still compressed, data of a contained file.
+pub struct ZipRawReader<'a, T:'a>
+{
+    inner: io::util::LimitReader<::util::RefMutReader<'a, T>>,
+}
+
+impl<'a, T: Reader> ZipRawReader<'a, T>
+{
+    /// Number of bytes of data that have not been read yet.
+    pub fn remaining(&self) -> u64
+    {
+        self.inner.limit() as u64
+    }
+}
+
+impl<'a, T: Reader> Reader for ZipRawReader<'a, T>
+{
+    fn read(&mut self, buf: &mut [u8]) -> IoResult<uint>
+    {
+        self.inner.read(buf)
+    }
+}
+
+/// Reader for the contents of a contained file that owns its source, as returned by `read_file_independent`.
+///
+/// It can be sent to another thread when the source can. The checksum is validated at the end.
+pub struct ZipFileReader<R>
+{
+    inner: Crc32Reader<::util::SizeLimitReader<Decompressor<R>>>,
+}
+
+impl<R: Reader> Reader for ZipFileReader<R>
+{
+    fn read(&mut self, buf: &mut [u8]) -> IoResult<uint>
+    {
+        self.inner.read(buf)
+    }
+}
+
+/// Decompressor for the supported methods that, unlike a boxed reader, keeps the type of its source.
+enum Decompressor<R>
+{
+    StoredData(io::util::LimitReader<R>),
+    DeflatedData(DeflateDecoder<io::util::LimitReader<R>>),
+}
+
+impl<R: Reader> Reader for Decompressor<R>
+{
+    fn read(&mut self, buf: &mut [u8]) -> IoResult<uint>
+    {
+        match *self
+        {
+            StoredData(ref mut reader) => reader.read(buf),
+            DeflatedData(ref mut reader) => reader.read(buf),
+        }
+    }
+}
+
+/// Iterator over the contained files that are not macOS metadata.
+pub struct FilesExcludingMacos<'a>
+{
+    inner: ::std::slice::Items<'a, ZipFile>,
+}
+
+impl<'a> Iterator<&'a ZipFile> for FilesExcludingMacos<'a>
+{
+    fn next(&mut self) -> Option<&'a ZipFile>
+    {
+        loop
+        {
+            match self.inner.next()
+            {
+                Some(file) if file.is_macos_metadata() => continue,
+                other => return other,
+            }
+        }
+    }
+}
+
+/// Iterator over the contained files together with their raw, still compressed, data.
+pub struct RawEntries<'a, T:'a>
+{
+    reader: &'a ZipReader<T>,
+    index: uint,
+}
+
+impl<'a, T: Reader+Seek> Iterator<IoResult<(ZipFile, Vec<u8>)>> for RawEntries<'a, T>
+{
+    fn next(&mut self) -> Option<IoResult<(ZipFile, Vec<u8>)>>
+    {
+        let file = match self.reader.files.as_slice().get(self.index)
+        {
+            Some(file) => file,
+            None => return None,
+        };
+        self.index += 1;
+
+        Some(self.reader.read_raw_to_vec(file).map(|data| (file.clone(), data)))
+    }
 }
 
 fn unsupported_zip_error<T>(detail: &str) -> IoResult<T>
@@ -48,27 +156,258 @@ fn unsupported_zip_error<T>(detail: &str) -> IoResult<T>
         })
 }
 
+fn strict_violation(desc: &'static str, file: &ZipFile) -> IoResult<()>
+{
+    Err(IoError
+        {
+            kind: io::OtherIoError,
+            desc: desc,
+            detail: Some(file.file_name.clone()),
+        })
+}
+
+fn check_supported(file: &ZipFile) -> IoResult<()>
+{
+    match file.unsupported_feature
+    {
+        Some(feature) => return unsupported_zip_error(feature),
+        None => {},
+    }
+    if file.encrypted
+    {
+        return unsupported_zip_error("Encrypted files can only be read with read_encrypted_to_vec")
+    }
+    check_supported_method(file)
+}
+
+fn check_supported_method(file: &ZipFile) -> IoResult<()>
+{
+    match file.compression_method
+    {
+        compression::Stored | compression::Deflated => Ok(()),
+        method => unsupported_zip_error(format!("Compression method {} is not supported", method).as_slice()),
+    }
+}
+
+/// Total length of the central directory header at `position`, from its length fields.
+fn central_header_length<R: Reader+Seek>(reader: &mut R, position: u64) -> IoResult<u64>
+{
+    static LENGTHS_OFFSET : u64 = 28;
+    try!(reader.seek((position + LENGTHS_OFFSET) as i64, io::SeekSet));
+    let file_name_length = try!(reader.read_le_u16()) as u64;
+    let extra_field_length = try!(reader.read_le_u16()) as u64;
+    let file_comment_length = try!(reader.read_le_u16()) as u64;
+    Ok(46 + file_name_length + extra_field_length + file_comment_length)
+}
+
+/// Wraps a reader positioned at the start of the data of a supported file.
+fn decompressing_reader<R: Reader>(reader: R, file: &ZipFile, max_uncompressed_size: Option<u64>) -> Box<Reader>
+{
+    box checked_reader(reader, file, max_uncompressed_size) as Box<Reader>
+}
+
+fn checked_reader<R: Reader>(reader: R, file: &ZipFile, max_uncompressed_size: Option<u64>) -> Crc32Reader<Box<Reader>>
+{
+    let limit_reader = io::util::LimitReader::new(reader, file.compressed_size as uint);
+
+    let reader = match file.compression_method
+    {
+        compression::Stored => box limit_reader as Box<Reader>,
+        compression::Deflated => box limit_reader.deflate_decode() as Box<Reader>,
+        _ => unreachable!(),
+    };
+
+    let reader = match max_uncompressed_size
+    {
+        Some(limit) => box ::util::SizeLimitReader::new(reader, limit) as Box<Reader>,
+        None => reader,
+    };
+
+    Crc32Reader::new(reader, file.crc32)
+}
+
 impl<T: Reader+Seek> ZipReader<T>
 {
     /// Opens a ZIP file and parses the content headers.
-    pub fn new(mut reader: T) -> IoResult<ZipReader<T>>
+    pub fn new(reader: T) -> IoResult<ZipReader<T>>
+    {
+        ZipReader::open(reader, 0, None, false, reader_spec::HeaderLimits::new())
+    }
+
+    /// Opens a ZIP file, rejecting headers that declare lengths beyond `limits`.
+    ///
+    /// Use this for untrusted input, with limits below the defaults of `HeaderLimits::new`. The
+    /// limits also apply to the local headers read later, such as by `validate_strict`.
+    pub fn new_with_limits(reader: T, limits: reader_spec::HeaderLimits) -> IoResult<ZipReader<T>>
+    {
+        ZipReader::open(reader, 0, None, false, limits)
+    }
+
+    /// Opens a ZIP file that may be slightly damaged, such as by padding at its end or a wrong
+    /// comment length. Damaged central directory headers are skipped; `files_lenient` reports
+    /// their errors.
+    pub fn new_tolerant(reader: T) -> IoResult<ZipReader<T>>
+    {
+        ZipReader::open(reader, 0, None, true, reader_spec::HeaderLimits::new())
+    }
+
+    /// Opens a ZIP file that starts at the given offset within the reader and ends at its end.
+    ///
+    /// All offsets stored in the ZIP file are taken relative to `start_offset`, which is useful
+    /// when a ZIP file is embedded in a larger file of which the layout is known. Use
+    /// `new_with_range` when other data follows the ZIP file.
+    pub fn new_with_offset(reader: T, start_offset: u64) -> IoResult<ZipReader<T>>
+    {
+        ZipReader::open(reader, start_offset, None, false, reader_spec::HeaderLimits::new())
+    }
+
+    /// Opens a ZIP file occupying `length` bytes from `start_offset` within the reader.
+    ///
+    /// Like `new_with_offset`, but the end of central directory record is searched for at the end
+    /// of the range, so data after the ZIP file is ignored.
+    pub fn new_with_range(reader: T, start_offset: u64, length: u64) -> IoResult<ZipReader<T>>
+    {
+        ZipReader::open(reader, start_offset, Some(start_offset + length), false, reader_spec::HeaderLimits::new())
+    }
+
+    fn open(mut reader: T, start_offset: u64, end: Option<u64>, tolerant: bool,
+            limits: reader_spec::HeaderLimits) -> IoResult<ZipReader<T>>
     {
-        let footer = try!(spec::CentralDirectoryEnd::find_and_parse(&mut reader));
+        let footer = match (tolerant, end)
+        {
+            (true, _) => try!(spec::CentralDirectoryEnd::find_and_parse_tolerant(&mut reader)),
+            (false, Some(end)) => try!(spec::CentralDirectoryEnd::find_and_parse_in(&mut reader, start_offset, end)),
+            (false, None) =>
+            {
+                try!(reader.seek(0, io::SeekEnd));
+                let end = try!(reader.tell());
+                try!(spec::CentralDirectoryEnd::find_and_parse_in(&mut reader, start_offset, end))
+            },
+        };
+
+        let footer_start = try!(reader.tell()) - 22 - footer.zip_file_comment.len() as u64;
 
         if footer.disk_number != footer.disk_with_central_directory { return unsupported_zip_error("Support for multi-disk files is not implemented") }
 
-        let directory_start = footer.central_directory_offset as i64;
-        let number_of_files = footer.number_of_files_on_this_disk as uint;
+        let mut directory_offset = footer.central_directory_offset as u64;
+        let mut directory_size = footer.central_directory_size as u64;
+        let mut number_of_files = footer.number_of_files_on_this_disk as uint;
+
+        // Values that do not fit are set to all ones, and stored in the ZIP64 record instead
+        if footer.number_of_files_on_this_disk == 0xFFFF || footer.central_directory_size == 0xFFFFFFFF
+            || footer.central_directory_offset == 0xFFFFFFFF
+        {
+            match try!(spec::Zip64CentralDirectoryEnd::find_and_parse(&mut reader, footer_start, start_offset))
+            {
+                Some(zip64_footer) =>
+                {
+                    if zip64_footer.disk_number != zip64_footer.disk_with_central_directory { return unsupported_zip_error("Support for multi-disk files is not implemented") }
+                    directory_offset = zip64_footer.central_directory_offset;
+                    directory_size = zip64_footer.central_directory_size;
+                    number_of_files = zip64_footer.number_of_files_on_this_disk as uint;
+                },
+                None => {},
+            }
+        }
+
+        let directory_start = (directory_offset + start_offset) as i64;
+        let directory_end = directory_start as u64 + directory_size;
 
-        let mut files = Vec::with_capacity(number_of_files);
+        // The declared number of files may be bogus; every central header takes at least 46 bytes
+        let mut files = Vec::with_capacity(::std::cmp::min(number_of_files as u64, directory_size / 46) as uint);
 
         try!(reader.seek(directory_start, io::SeekSet));
+        let mut headers_seen = 0u;
         for _ in range(0, number_of_files)
         {
-            files.push(try!(reader_spec::central_header_to_zip_file(&mut reader)));
+            if !try!(reader_spec::at_central_header(&mut reader)) { break }
+            headers_seen += 1;
+
+            let position = try!(reader.tell());
+            match reader_spec::central_header_to_zip_file(&mut reader, start_offset, directory_end, &limits)
+            {
+                Ok(file) => files.push(file),
+                Err(e) =>
+                {
+                    if !tolerant { return Err(e) }
+                    warn!("Skipping a damaged central directory header: {}", e);
+                    let length = try!(central_header_length(&mut reader, position));
+                    try!(reader.seek((position + length) as i64, io::SeekSet));
+                },
+            }
+        }
+
+        let count_mismatch = headers_seen != number_of_files || try!(reader_spec::at_central_header(&mut reader));
+        if count_mismatch
+        {
+            warn!("The central directory does not contain the {} files it declares", number_of_files);
+        }
+
+        let mut zip = ZipReader
+        {
+            inner: RefCell::new(reader),
+            files: files,
+            count_mismatch: count_mismatch,
+            archive_offset: start_offset,
+            max_uncompressed_size: None,
+            directory_start: directory_start as u64,
+            directory_size: directory_size,
+            limits: limits,
+        };
+        try!(zip.recover_data_descriptor_sizes());
+        Ok(zip)
+    }
+
+    /// Take the checksum and sizes of files from their data descriptors when the central directory has none.
+    ///
+    /// Some streaming writers set bit 3 and leave the checksum and sizes zero in the central
+    /// directory, so their files look empty. For each such file, this searches its data for the
+    /// descriptor, up to where the next file or the central directory starts. Files without a
+    /// recognizable descriptor are left unchanged.
+    fn recover_data_descriptor_sizes(&mut self) -> IoResult<()>
+    {
+        // Visit the files in the order of their data, so the next one bounds the search
+        let mut order: Vec<uint> = range(0, self.files.len()).collect();
+        order.sort_by(|&a, &b| self.files[a].header_start.cmp(&self.files[b].header_start));
+
+        let mut recovered = Vec::new();
+        {
+            let mut reader = try!(self.borrow_inner());
+            for (i, &index) in order.iter().enumerate()
+            {
+                let file = &self.files[index];
+                if !file.using_data_descriptor || file.compressed_size != 0 || file.uncompressed_size != 0 { continue }
+
+                let limit = match order.as_slice().get(i + 1)
+                {
+                    Some(&next) => self.files[next].header_start,
+                    None => self.directory_start,
+                };
+                match try!(reader_spec::find_data_descriptor(&mut *reader, file.data_start, limit))
+                {
+                    Some(descriptor) => recovered.push((index, descriptor)),
+                    None => warn!("No data descriptor found for {}", file.file_name),
+                }
+            }
+        }
+
+        for (index, (crc32, compressed_size, uncompressed_size)) in recovered.into_iter()
+        {
+            let file = &mut self.files[index];
+            file.crc32 = crc32;
+            file.compressed_size = compressed_size;
+            file.uncompressed_size = uncompressed_size;
         }
+        Ok(())
+    }
 
-        Ok(ZipReader { inner: RefCell::new(reader), files: files })
+    /// Limit the number of bytes a single file may decompress to (unlimited by default).
+    ///
+    /// Reading a file fails once it produces more bytes than the limit. This counts the actual
+    /// output, so it also protects against files that declare a false uncompressed size.
+    pub fn set_max_uncompressed_size(&mut self, bytes: u64)
+    {
+        self.max_uncompressed_size = Some(bytes);
     }
 
     /// An iterator over the information of all contained files.
@@ -77,64 +416,1365 @@ impl<T: Reader+Seek> ZipReader<T>
         self.files.as_slice().iter()
     }
 
-    /// Gets a reader for a contained zipfile.
+    /// An iterator over all contained files and their raw data, without decompressing anything.
     ///
-    /// Possible errors:
+    /// The data can be written to another ZIP file with `ZipWriter::raw_write_file`. Only one file
+    /// is kept in memory at a time.
+    pub fn raw_entries<'a>(&'a self) -> RawEntries<'a, T>
+    {
+        RawEntries { reader: self, index: 0 }
+    }
+
+    /// The information of all contained files, in the order in which their data is stored.
     ///
-    /// * `ResourceUnavailable`: when another reader returned from this function is still active
-    /// * `OtherIoError`: if the file is encrypted or has an unsupported compression
-    pub fn read_file(&self, file: &ZipFile) -> IoResult<Box<Reader>>
+    /// The central directory may list files in any order. Processing them by offset reads the ZIP
+    /// file sequentially.
+    pub fn files_by_offset(&self) -> ::std::vec::MoveItems<&ZipFile>
+    {
+        let mut files: Vec<&ZipFile> = self.files.iter().collect();
+        files.sort_by(|a, b| a.header_start.cmp(&b.header_start));
+        files.into_iter()
+    }
+
+    /// An iterator over the information of all contained files, except macOS metadata.
+    ///
+    /// See `ZipFile::is_macos_metadata` for the files that are left out.
+    pub fn files_excluding_macos(&self) -> FilesExcludingMacos
+    {
+        FilesExcludingMacos { inner: self.files.as_slice().iter() }
+    }
+
+    /// Parse the central directory again, giving the result for every header separately.
+    ///
+    /// Unlike `files`, a damaged header does not hide the others: its error is yielded, and parsing
+    /// continues after it. As `new` fails on the first damaged header, this is mostly useful on a
+    /// ZipReader opened with `new_tolerant`, whose `files` leaves the damaged headers out.
+    pub fn files_lenient(&self) -> ::std::vec::MoveItems<IoResult<ZipFile>>
     {
-        let mut inner_reader = match self.inner.try_borrow_mut()
+        let mut results = Vec::new();
+        match self.borrow_inner()
         {
-            Some(reader) => reader,
-            None => return Err(IoError
-                               {
-                                   kind: io::ResourceUnavailable,
-                                   desc: "There is already a ZIP reader active",
-                                   detail: None
-                               }),
+            Err(e) => results.push(Err(e)),
+            Ok(mut reader) =>
+            {
+                let directory_end = self.directory_start + self.directory_size;
+                let mut position = self.directory_start;
+                while position < directory_end
+                {
+                    let result = match reader.seek(position as i64, io::SeekSet)
+                    {
+                        Ok(()) => reader_spec::central_header_to_zip_file(&mut *reader, self.archive_offset, directory_end, &self.limits),
+                        Err(e) => Err(e),
+                    };
+                    results.push(result);
+                    position = match central_header_length(&mut *reader, position)
+                    {
+                        Ok(length) => position + length,
+                        Err(_) => break,
+                    };
+                }
+            },
+        }
+        results.into_iter()
+    }
+
+    /// Number of contained files.
+    pub fn len(&self) -> uint
+    {
+        self.files.len()
+    }
+
+    /// True if the ZIP file contains no files.
+    pub fn is_empty(&self) -> bool
+    {
+        self.files.is_empty()
+    }
+
+    /// Find the information of a contained file by its name.
+    pub fn by_name(&self, name: &str) -> Option<&ZipFile>
+    {
+        self.files.iter().find(|file| file.file_name.as_slice() == name)
+    }
+
+    /// True if the number of files in the central directory differs from the declared number.
+    ///
+    /// Parsing stops at the declared number or at the first missing header, whichever comes first.
+    pub fn count_mismatch(&self) -> bool
+    {
+        self.count_mismatch
+    }
+
+    /// Summarize the information of all contained files.
+    pub fn info(&self) -> ArchiveInfo
+    {
+        let mut info = ArchiveInfo
+        {
+            number_of_files: self.files.len(),
+            compressed_size: 0,
+            uncompressed_size: 0,
+            encrypted: false,
+            compression_methods: self.compression_methods(),
         };
-        let pos = file.data_start as i64;
 
-        if file.encrypted
+        for file in self.files.iter()
         {
-            return unsupported_zip_error("Encrypted files are not supported")
+            info.compressed_size += file.compressed_size;
+            info.uncompressed_size += file.uncompressed_size;
+            info.encrypted |= file.encrypted;
         }
 
-        try!(inner_reader.seek(pos, io::SeekSet));
-        let refmut_reader = ::util::RefMutReader::new(inner_reader);
-        let limit_reader = io::util::LimitReader::new(refmut_reader, file.compressed_size as uint);
+        info
+    }
 
-        let reader = match file.compression_method
+    /// Summed compressed and uncompressed sizes of the files for which `pred` returns true.
+    pub fn size_of(&self, pred: |&ZipFile| -> bool) -> (u64, u64)
+    {
+        let mut compressed = 0;
+        let mut uncompressed = 0;
+        for file in self.files.iter()
+        {
+            if !pred(file) { continue }
+            compressed += file.compressed_size;
+            uncompressed += file.uncompressed_size;
+        }
+        (compressed, uncompressed)
+    }
+
+    /// The distinct compression methods used by the contained files, in order of first use.
+    pub fn compression_methods(&self) -> Vec<compression::CompressionMethod>
+    {
+        let mut methods = Vec::new();
+        for file in self.files.iter()
         {
-            compression::Stored =>
+            if !methods.contains(&file.compression_method)
             {
-                box
-                    Crc32Reader::new(
-                        limit_reader,
-                        file.crc32)
-                    as Box<Reader>
-            },
-            compression::Deflated =>
-            {
-                let deflate_reader = limit_reader.deflate_decode();
-                box
-                    Crc32Reader::new(
-                        deflate_reader,
-                        file.crc32)
-                    as Box<Reader>
-            },
-            _ => return unsupported_zip_error("Compression method not supported"),
-        };
-        Ok(reader)
+                methods.push(file.compression_method);
+            }
+        }
+        methods
     }
 
-    /// Unwrap and return the inner reader object
+    /// True if the local headers and data of two files overlap.
     ///
-    /// The position of the reader is undefined.
-    pub fn unwrap(self) -> T
+    /// Well-formed ZIP files never share data between files. Overlapping files are typical of
+    /// crafted ZIP files, such as those that decompress to far more than their size.
+    pub fn has_overlapping_entries(&self) -> bool
     {
-        self.inner.unwrap()
+        let mut ranges: Vec<(u64, u64)> = self.files.iter()
+            .map(|file| (file.header_start, file.data_start + file.compressed_size))
+            .collect();
+        ranges.sort();
+        ranges.windows(2).any(|pair|
+        {
+            let (_, previous_end) = pair[0];
+            let (next_start, _) = pair[1];
+            next_start < previous_end
+        })
+    }
+
+    /// Check the ZIP file against the specification, returning the first violation found.
+    ///
+    /// For every file the local header is compared with the central one: the names and flags must
+    /// agree, as must the checksum and sizes unless they are stored in a data descriptor. The
+    /// version needed must be high enough for the features used. Finally no two files may overlap.
+    pub fn validate_strict(&self) -> IoResult<()>
+    {
+        for file in self.files.iter()
+        {
+            let local = {
+                let mut reader = try!(self.borrow_inner());
+                try!(reader.seek(file.header_start as i64, io::SeekSet));
+                try!(reader_spec::local_header_to_zip_file(&mut *reader, &self.limits))
+            };
+
+            if local.file_name != file.file_name
+            {
+                return strict_violation("File name in local header differs from central header", file)
+            }
+            // Bit 11 may also mark a UTF-8 comment, which only the central header has
+            if local.flags & !(1 << 11) != file.flags & !(1 << 11)
+            {
+                return strict_violation("Flags in local header differ from central header", file)
+            }
+            if !file.using_data_descriptor && local.crc32 != file.crc32
+            {
+                return strict_violation("CRC-32 in local header differs from central header", file)
+            }
+            if !file.using_data_descriptor
+                && (local.compressed_size != file.compressed_size || local.uncompressed_size != file.uncompressed_size)
+            {
+                return strict_violation("Sizes in local header differ from central header", file)
+            }
+            if file.version_needed < file.required_version()
+            {
+                return strict_violation("Version needed to extract is too low for the file", file)
+            }
+        }
+
+        if self.has_overlapping_entries()
+        {
+            return Err(IoError
+                       {
+                           kind: io::OtherIoError,
+                           desc: "Files overlap each other",
+                           detail: None,
+                       })
+        }
+
+        Ok(())
+    }
+
+    /// Minimum version of the specification needed to extract all contained files.
+    ///
+    /// This is the highest `ZipFile::required_version` of the files, or 10 for an empty ZIP file.
+    pub fn required_version(&self) -> u16
+    {
+        self.files.iter().map(|file| file.required_version()).max().unwrap_or(10)
+    }
+
+    /// Sum of the uncompressed sizes of all contained files.
+    ///
+    /// Returns None when the total is unknown: a file written with a data descriptor that records an
+    /// uncompressed size of 0 may actually contain data.
+    pub fn total_uncompressed_size(&self) -> Option<u64>
+    {
+        let mut total = 0;
+        for file in self.files.iter()
+        {
+            if file.using_data_descriptor && file.uncompressed_size == 0 { return None }
+            total += file.uncompressed_size;
+        }
+        Some(total)
+    }
+
+    /// Checksum of the central directory, for quickly comparing ZIP files.
+    ///
+    /// ZIP files with the same files, metadata and layout have the same digest.
+    pub fn central_directory_digest(&self) -> IoResult<u32>
+    {
+        let directory = try!(self.central_directory_bytes());
+        Ok(::crc32::update(0, directory.as_slice()))
+    }
+
+    /// The central directory exactly as stored, for example to compute or verify a signature over it.
+    ///
+    /// Each header in it can be parsed with `reader_spec::parse_central_header_from_slice`.
+    pub fn central_directory_bytes(&self) -> IoResult<Vec<u8>>
+    {
+        let mut inner_reader = try!(self.borrow_inner());
+        try!(inner_reader.seek(self.directory_start as i64, io::SeekSet));
+        try!(reader_spec::check_available(&mut *inner_reader, self.directory_size));
+        inner_reader.read_exact(self.directory_size as uint)
+    }
+
+    /// Gets a reader for a contained zipfile.
+    ///
+    /// Possible errors:
+    ///
+    /// * `ResourceUnavailable`: when another reader returned from this function is still active
+    /// * `OtherIoError`: if the file is encrypted or has an unsupported compression
+    ///
+    /// An unsupported file is rejected before the underlying reader is touched, so the error can be
+    /// skipped and the other files can still be read.
+    pub fn read_file(&self, file: &ZipFile) -> IoResult<Box<Reader>>
+    {
+        try!(check_supported(file));
+
+        let mut inner_reader = try!(self.borrow_inner());
+        let pos = file.data_start as i64;
+
+        try!(inner_reader.seek(pos, io::SeekSet));
+        let refmut_reader = ::util::RefMutReader::new(inner_reader);
+        Ok(decompressing_reader(refmut_reader, file, self.max_uncompressed_size))
+    }
+
+    /// Gets a reader for a contained file that reports whether the checksum matched.
+    ///
+    /// Like `read_file`, reading fails at the end when the checksum does not match. Afterwards
+    /// `crc_ok` gives the outcome, so callers can check a flag instead of the error.
+    pub fn read_file_checked(&self, file: &ZipFile) -> IoResult<Crc32Reader<Box<Reader>>>
+    {
+        try!(check_supported(file));
+
+        let mut inner_reader = try!(self.borrow_inner());
+        try!(inner_reader.seek(file.data_start as i64, io::SeekSet));
+        let refmut_reader = ::util::RefMutReader::new(inner_reader);
+        Ok(checked_reader(refmut_reader, file, self.max_uncompressed_size))
+    }
+
+    /// Gets a reader for the raw data of a contained zipfile, without decompressing it.
+    ///
+    /// The checksum is not validated. The same restriction on active readers as for `read_file`
+    /// applies.
+    pub fn read_raw_file<'a>(&'a self, file: &ZipFile) -> IoResult<ZipRawReader<'a, T>>
+    {
+        let mut inner_reader = try!(self.borrow_inner());
+        try!(inner_reader.seek(file.data_start as i64, io::SeekSet));
+        let refmut_reader = ::util::RefMutReader::new(inner_reader);
+
+        Ok(ZipRawReader { inner: io::util::LimitReader::new(refmut_reader, file.compressed_size as uint) })
+    }
+
+    /// Read the raw data of a file into memory, once it is known that the reader holds that much.
+    fn read_raw_to_vec(&self, file: &ZipFile) -> IoResult<Vec<u8>>
+    {
+        let mut inner_reader = try!(self.borrow_inner());
+        try!(inner_reader.seek(file.data_start as i64, io::SeekSet));
+        try!(reader_spec::check_available(&mut *inner_reader, file.compressed_size));
+        inner_reader.read_exact(file.compressed_size as uint)
+    }
+
+    /// Read `len` bytes starting at `start` from the contents of a Stored file.
+    ///
+    /// Only the requested bytes are read. The checksum is not validated, as that needs all data.
+    pub fn read_range(&self, file: &ZipFile, start: u64, len: u64) -> IoResult<Vec<u8>>
+    {
+        try!(check_supported(file));
+        if file.compression_method != compression::Stored
+        {
+            return Err(IoError { kind: io::InvalidInput, desc: "Ranges can only be read from Stored files", detail: None })
+        }
+        if start > file.uncompressed_size || len > file.uncompressed_size - start
+        {
+            return Err(IoError { kind: io::InvalidInput, desc: "Range exceeds the size of the file", detail: None })
+        }
+
+        let mut inner_reader = try!(self.borrow_inner());
+        try!(inner_reader.seek((file.data_start + start) as i64, io::SeekSet));
+        try!(reader_spec::check_available(&mut *inner_reader, len));
+        inner_reader.read_exact(len as uint)
+    }
+
+    fn borrow_inner(&self) -> IoResult<RefMut<T>>
+    {
+        match self.inner.try_borrow_mut()
+        {
+            Some(reader) => Ok(reader),
+            None => Err(IoError
+                        {
+                            kind: io::ResourceUnavailable,
+                            desc: "There is already a ZIP reader active",
+                            detail: None
+                        }),
+        }
+    }
+
+    /// Extract a contained file into a writer.
+    ///
+    /// The checksum is validated once all data has been copied.
+    pub fn extract_entry_to<W: Writer>(&self, file: &ZipFile, out: &mut W) -> IoResult<()>
+    {
+        let mut reader = try!(self.read_file(file));
+        io::util::copy(&mut reader, out)
+    }
+
+    /// Decompress a contained file, calling `f` with each chunk of data.
+    ///
+    /// An error returned by `f` stops decompression and is returned. The checksum is validated once
+    /// all data has been passed to `f`.
+    pub fn stream_entry(&self, file: &ZipFile, f: |&[u8]| -> IoResult<()>) -> IoResult<()>
+    {
+        let mut reader = try!(self.read_file(file));
+        let mut buf = [0u8, ..64 * 1024];
+        loop
+        {
+            let count = match reader.read(&mut buf)
+            {
+                Ok(count) => count,
+                Err(ref e) if e.kind == io::EndOfFile => return Ok(()),
+                Err(e) => return Err(e),
+            };
+            try!(f(buf.slice_to(count)));
+        }
+    }
+
+    /// Read the full contents of the file with the given name.
+    ///
+    /// Returns a `FileNotFound` error when no such file exists.
+    pub fn read_file_to_vec(&self, name: &str) -> IoResult<Vec<u8>>
+    {
+        let file = match self.by_name(name)
+        {
+            Some(file) => file,
+            None => return Err(IoError { kind: io::FileNotFound, desc: "No file with this name in the ZIP", detail: Some(name.to_string()) }),
+        };
+        // The declared size may be bogus, so do not reserve more than a reasonable amount up front
+        let capacity = ::std::cmp::min(file.uncompressed_size, 1 << 24) as uint;
+        let mut writer = io::MemWriter::with_capacity(capacity);
+        try!(self.extract_entry_to(file, &mut writer));
+        Ok(writer.unwrap())
+    }
+
+    /// Read the full contents of the file with the given name, decrypting it with `password`.
+    ///
+    /// Only the traditional PKWARE encryption is supported. A wrong password almost always fails
+    /// with `InvalidInput` before anything is decompressed, and otherwise fails the checksum. Files
+    /// that are not encrypted are read as by `read_file_to_vec`.
+    pub fn read_encrypted_to_vec(&self, name: &str, password: &[u8]) -> IoResult<Vec<u8>>
+    {
+        let file = match self.by_name(name)
+        {
+            Some(file) => file,
+            None => return Err(IoError { kind: io::FileNotFound, desc: "No file with this name in the ZIP", detail: Some(name.to_string()) }),
+        };
+        if !file.encrypted { return self.read_file_to_vec(name) }
+        match file.unsupported_feature
+        {
+            Some(feature) => return unsupported_zip_error(feature),
+            None => {},
+        }
+        try!(check_supported_method(file));
+        if file.compressed_size < ::zipcrypto::ENCRYPTION_HEADER_SIZE
+        {
+            return Err(IoError { kind: io::MismatchedFileTypeForOperation, desc: "Encrypted file is shorter than its encryption header", detail: Some(name.to_string()) })
+        }
+
+        // With a data descriptor the checksum may not be known while writing the header, so the time is used instead
+        let check_byte = match file.using_data_descriptor
+        {
+            true =>
+            {
+                let (time, _) = ::util::tm_to_msdos_datetime(file.last_modified_time);
+                (time >> 8) as u8
+            },
+            false => (file.crc32 >> 24) as u8,
+        };
+
+        let mut inner_reader = try!(self.borrow_inner());
+        try!(inner_reader.seek(file.data_start as i64, io::SeekSet));
+        let raw_reader = io::util::LimitReader::new(::util::RefMutReader::new(inner_reader), file.compressed_size as uint);
+        let decrypted = try!(::zipcrypto::ZipCryptoReader::new(raw_reader, password, check_byte));
+        let mut reader = checked_reader(decrypted, file, self.max_uncompressed_size);
+
+        let capacity = ::std::cmp::min(file.uncompressed_size, 1 << 24) as uint;
+        let mut writer = io::MemWriter::with_capacity(capacity);
+        try!(io::util::copy(&mut reader, &mut writer));
+        Ok(writer.unwrap())
+    }
+
+    /// Open the file named `name`, which itself is a ZIP file.
+    ///
+    /// The contained ZIP file is read into memory.
+    pub fn open_nested(&self, name: &str) -> IoResult<ZipReader<io::MemReader>>
+    {
+        let data = try!(self.read_file_to_vec(name));
+        ZipReader::new(io::MemReader::new(data))
+    }
+
+    /// Decompress the file with the given name into `buf`, returning the number of bytes written.
+    ///
+    /// Fails when `buf` is smaller than the uncompressed size of the file.
+    pub fn read_file_into(&self, name: &str, buf: &mut [u8]) -> IoResult<uint>
+    {
+        let file = match self.by_name(name)
+        {
+            Some(file) => file,
+            None => return Err(IoError { kind: io::FileNotFound, desc: "No file with this name in the ZIP", detail: Some(name.to_string()) }),
+        };
+        if (buf.len() as u64) < file.uncompressed_size
+        {
+            return Err(IoError { kind: io::InvalidInput, desc: "Buffer is smaller than the uncompressed size", detail: Some(name.to_string()) })
+        }
+        let mut writer = io::BufWriter::new(buf);
+        try!(self.extract_entry_to(file, &mut writer));
+        Ok(try!(writer.tell()) as uint)
+    }
+
+    /// Parse the first file of the ZIP file directly from its local header.
+    ///
+    /// The local header is read at the start of the ZIP file, which is the offset given to
+    /// `new_with_offset` or 0, without consulting the central directory. See `first_file_at`.
+    pub fn first_file(&mut self) -> IoResult<ZipFile>
+    {
+        let mut reader = try!(self.borrow_inner());
+        try!(reader.seek(self.archive_offset as i64, io::SeekSet));
+        reader_spec::local_header_to_zip_file(&mut *reader, &self.limits)
+    }
+
+    /// Parse the first file of a ZIP file directly from its local header at `start_offset`.
+    ///
+    /// Neither the end of central directory record nor the central directory is read, so this is
+    /// cheap even for very large ZIP files. Pass the length of the stub of a self-extracting
+    /// archive as `start_offset`, or 0 otherwise. Information only present in the central
+    /// directory, such as the file comment, is empty. When the file was written with a data
+    /// descriptor, the checksum and sizes are zero.
+    pub fn first_file_at(reader: &mut T, start_offset: u64) -> IoResult<ZipFile>
+    {
+        try!(reader.seek(start_offset as i64, io::SeekSet));
+        reader_spec::local_header_to_zip_file(reader, &reader_spec::HeaderLimits::new())
+    }
+
+    /// Return the inner reader, for example to continue reading after an embedded ZIP file.
+    ///
+    /// The position of the reader is undefined.
+    pub fn into_inner(self) -> T
+    {
+        self.inner.unwrap()
+    }
+
+    /// Get a reference to the inner reader.
+    ///
+    /// Fails with `ResourceUnavailable` while a reader returned by `read_file` is still active.
+    pub fn get_ref(&self) -> IoResult<Ref<T>>
+    {
+        match self.inner.try_borrow()
+        {
+            Some(reader) => Ok(reader),
+            None => Err(IoError
+                        {
+                            kind: io::ResourceUnavailable,
+                            desc: "There is already a ZIP reader active",
+                            detail: None
+                        }),
+        }
+    }
+
+    /// Unwrap and return the inner reader object
+    ///
+    /// The position of the reader is undefined.
+    #[deprecated = "renamed to into_inner"]
+    pub fn unwrap(self) -> T
+    {
+        self.into_inner()
+    }
+}
+
+impl<T: Reader+Seek+Clone> ZipReader<T>
+{
+    /// Gets a reader for a contained file that has its own clone of the underlying reader.
+    ///
+    /// Any number of these readers can be active at once, and each can be sent to another thread
+    /// when `T` can, for example to extract several files in parallel. `SharedMemReader` is a cheap
+    /// source for this, as its clones share the data. Cloning fails with `ResourceUnavailable`
+    /// while a reader returned by `read_file` is active.
+    pub fn read_file_independent(&self, file: &ZipFile) -> IoResult<ZipFileReader<T>>
+    {
+        try!(check_supported(file));
+
+        let mut reader = match self.inner.try_borrow()
+        {
+            Some(reader) => reader.clone(),
+            None => return Err(IoError
+                               {
+                                   kind: io::ResourceUnavailable,
+                                   desc: "There is already a ZIP reader active",
+                                   detail: None
+                               }),
+        };
+
+        try!(reader.seek(file.data_start as i64, io::SeekSet));
+        let limit_reader = io::util::LimitReader::new(reader, file.compressed_size as uint);
+        let decompressor = match file.compression_method
+        {
+            compression::Stored => StoredData(limit_reader),
+            compression::Deflated => DeflatedData(limit_reader.deflate_decode()),
+            _ => unreachable!(),
+        };
+        let max_uncompressed_size = self.max_uncompressed_size.unwrap_or(::std::u64::MAX);
+        let size_limit_reader = ::util::SizeLimitReader::new(decompressor, max_uncompressed_size);
+        Ok(ZipFileReader { inner: Crc32Reader::new(size_limit_reader, file.crc32) })
+    }
+}
+
+#[cfg(test)]
+mod test
+{
+    use testutil;
+    use compression;
+    use crc32;
+    use spec;
+    use time;
+    use std::io;
+
+    /// ZIP file of Stored files of which the checksum and sizes are only in data descriptors.
+    fn data_descriptor_zip(files: &[(&str, &[u8])], signature: bool, zip64: bool) -> Vec<u8>
+    {
+        let mut writer = io::MemWriter::new();
+        let mut central = io::MemWriter::new();
+        for &(name, data) in files.iter()
+        {
+            let offset = writer.get_ref().len() as u32;
+            writer.write_le_u32(spec::LOCAL_FILE_HEADER_SIGNATURE).unwrap();
+            writer.write_le_u16(20).unwrap();
+            writer.write_le_u16(1 << 3).unwrap();
+            // Method, time, date, checksum and sizes
+            writer.write(Vec::from_elem(18, 0u8).as_slice()).unwrap();
+            writer.write_le_u16(name.len() as u16).unwrap();
+            writer.write_le_u16(0).unwrap();
+            writer.write(name.as_bytes()).unwrap();
+            writer.write(data).unwrap();
+
+            if signature { writer.write_le_u32(spec::DATA_DESCRIPTOR_SIGNATURE).unwrap(); }
+            writer.write_le_u32(crc32::update(0, data)).unwrap();
+            if zip64
+            {
+                writer.write_le_u64(data.len() as u64).unwrap();
+                writer.write_le_u64(data.len() as u64).unwrap();
+            }
+            else
+            {
+                writer.write_le_u32(data.len() as u32).unwrap();
+                writer.write_le_u32(data.len() as u32).unwrap();
+            }
+
+            central.write_le_u32(spec::CENTRAL_DIRECTORY_HEADER_SIGNATURE).unwrap();
+            central.write_le_u16(20).unwrap();
+            central.write_le_u16(20).unwrap();
+            central.write_le_u16(1 << 3).unwrap();
+            central.write(Vec::from_elem(18, 0u8).as_slice()).unwrap();
+            central.write_le_u16(name.len() as u16).unwrap();
+            // Extra field and comment lengths, disk number and attributes
+            central.write(Vec::from_elem(12, 0u8).as_slice()).unwrap();
+            central.write_le_u32(offset).unwrap();
+            central.write(name.as_bytes()).unwrap();
+        }
+
+        let central_start = writer.get_ref().len() as u32;
+        writer.write(central.get_ref()).unwrap();
+        let footer = spec::CentralDirectoryEnd
+        {
+            disk_number: 0,
+            disk_with_central_directory: 0,
+            number_of_files_on_this_disk: files.len() as u16,
+            number_of_files: files.len() as u16,
+            central_directory_size: central.get_ref().len() as u32,
+            central_directory_offset: central_start,
+            zip_file_comment: Vec::new(),
+        };
+        footer.write(&mut writer).unwrap();
+        writer.unwrap()
+    }
+
+    #[test]
+    fn sizes_are_recovered_from_data_descriptors()
+    {
+        // The first file contains a signature that is not followed by its size
+        let files: &[(&str, &[u8])] = &[("a.txt", b"PK\x07\x08 not a descriptor"), ("b.txt", b"second")];
+        for &(signature, zip64) in [(true, false), (false, false), (true, true), (false, true)].iter()
+        {
+            let zip = testutil::open_zip(data_descriptor_zip(files, signature, zip64));
+            for &(name, data) in files.iter()
+            {
+                let file = zip.by_name(name).unwrap();
+                assert_eq!(file.compressed_size, data.len() as u64);
+                assert_eq!(file.uncompressed_size, data.len() as u64);
+                assert_eq!(zip.read_file_to_vec(name).unwrap().as_slice(), data);
+            }
+        }
+    }
+
+    #[test]
+    fn sizes_are_recovered_across_read_chunks()
+    {
+        let data: Vec<u8> = range(0u, 150000).map(|i| (i % 251) as u8).collect();
+        let mut archive = testutil::write_zip(|zip|
+        {
+            zip.set_use_data_descriptor(true);
+            try!(zip.start_file("large.bin", compression::Stored));
+            try!(zip.write(data.as_slice()));
+            try!(zip.start_file("small.txt", compression::Deflated));
+            zip.write(b"small")
+        });
+        // Clear the checksum and sizes in the central directory, as some streaming writers do
+        for &central in testutil::positions(archive.as_slice(), b"PK\x01\x02").iter()
+        {
+            for byte in archive.slice_mut(central + 16, central + 28).iter_mut() { *byte = 0; }
+        }
+
+        let zip = testutil::open_zip(archive);
+        assert_eq!(zip.by_name("large.bin").unwrap().compressed_size, 150000);
+        assert_eq!(zip.read_file_to_vec("large.bin").unwrap(), data);
+        assert_eq!(zip.read_file_to_vec("small.txt").unwrap().as_slice(), b"small");
+    }
+
+    #[test]
+    fn descriptor_search_stops_at_next_file()
+    {
+        let mut data = data_descriptor_zip(&[("a.txt", b"first"), ("b.txt", b"first")], true, false);
+        // Break the compressed size in the first descriptor
+        let descriptor = testutil::positions(data.as_slice(), b"PK\x07\x08")[0];
+        testutil::put_le_u16(data.as_mut_slice(), descriptor + 8, 6);
+
+        let zip = testutil::open_zip(data);
+        assert_eq!(zip.by_name("a.txt").unwrap().compressed_size, 0);
+        assert_eq!(zip.by_name("b.txt").unwrap().compressed_size, 5);
+    }
+
+    #[test]
+    fn unsupported_file_is_skipped()
+    {
+        let mut data = testutil::stored_zip(&[("stored.txt", b"stored data"), ("other.txt", b"other data")]);
+        // Mark the second file as LZMA compressed in the central directory
+        let second = testutil::positions(data.as_slice(), b"PK\x01\x02")[1];
+        testutil::put_le_u16(data.as_mut_slice(), second + 10, 14);
+        let zip = testutil::open_zip(data);
+
+        let other = zip.by_name("other.txt").unwrap();
+        let error = zip.read_file(other).err().unwrap();
+        assert_eq!(error.desc, "This ZIP file is not supported");
+        assert_eq!(error.detail, Some("Compression method LZMA is not supported".to_string()));
+        let stored = zip.by_name("stored.txt").unwrap();
+        assert_eq!(zip.read_file(stored).unwrap().read_to_end().unwrap().as_slice(), b"stored data");
+    }
+
+    #[test]
+    fn first_file_is_first_written()
+    {
+        let data = testutil::stored_zip(&[("first.txt", b"1"), ("second.txt", b"2")]);
+        let mut zip = testutil::open_zip(data.clone());
+        let file = zip.first_file().unwrap();
+        assert_eq!(file.file_name.as_slice(), "first.txt");
+        assert_eq!(file.uncompressed_size, 1);
+
+        // Embedded after a prefix, the first file starts at the given offset
+        let mut container = Vec::from_elem(16, 0u8);
+        container.push_all(data.as_slice());
+        let mut zip = super::ZipReader::new_with_offset(io::MemReader::new(container), 16).unwrap();
+        let file = zip.first_file().unwrap();
+        assert_eq!(file.file_name.as_slice(), "first.txt");
+        assert_eq!(file.header_start, 16);
+    }
+
+    #[test]
+    fn first_file_ignores_central_directory()
+    {
+        let mut data = Vec::from_elem(100, 0x90u8);
+        data.push_all(testutil::stored_zip(&[("first.txt", b"1"), ("second.txt", b"2")]).as_slice());
+        // Without an end of central directory record, opening the ZIP file would fail
+        let footer = testutil::positions(data.as_slice(), b"PK\x05\x06")[0];
+        data.truncate(footer);
+
+        let file = super::ZipReader::first_file_at(&mut io::MemReader::new(data), 100).unwrap();
+        assert_eq!(file.file_name.as_slice(), "first.txt");
+        assert_eq!(file.header_start, 100);
+    }
+
+    #[test]
+    fn info_sums_files()
+    {
+        let text = Vec::from_elem(1000, b'a');
+        let zip = testutil::open_zip(testutil::write_zip(|zip|
+        {
+            try!(zip.start_file("stored.txt", compression::Stored));
+            try!(zip.write(b"hello"));
+            try!(zip.start_file("deflated.txt", compression::Deflated));
+            zip.write(text.as_slice())
+        }));
+
+        let info = zip.info();
+        let deflated = zip.by_name("deflated.txt").unwrap();
+        assert_eq!(info.number_of_files, 2);
+        assert_eq!(info.uncompressed_size, 1005);
+        assert_eq!(info.compressed_size, 5 + deflated.compressed_size);
+        assert!(!info.encrypted);
+        assert_eq!(info.compression_methods, vec![compression::Stored, compression::Deflated]);
+    }
+
+    #[test]
+    fn extract_entry_to_writer()
+    {
+        let text = Vec::from_elem(5000, b'z');
+        let zip = testutil::open_zip(testutil::write_zip(|zip|
+        {
+            try!(zip.start_file("z.txt", compression::Deflated));
+            zip.write(text.as_slice())
+        }));
+
+        let mut writer = io::MemWriter::new();
+        zip.extract_entry_to(zip.by_name("z.txt").unwrap(), &mut writer).unwrap();
+        assert_eq!(writer.get_ref(), text.as_slice());
+    }
+
+    #[test]
+    fn count_mismatch_is_reported()
+    {
+        let mut data = testutil::stored_zip(&[("a.txt", b"a"), ("b.txt", b"b")]);
+        // Claim three files in the end of central directory record
+        let footer = testutil::positions(data.as_slice(), b"PK\x05\x06")[0];
+        testutil::put_le_u16(data.as_mut_slice(), footer + 8, 3);
+        testutil::put_le_u16(data.as_mut_slice(), footer + 10, 3);
+
+        let zip = testutil::open_zip(data);
+        assert_eq!(zip.len(), 2);
+        assert!(zip.count_mismatch());
+    }
+
+    #[test]
+    fn read_file_to_vec_returns_contents()
+    {
+        let zip = testutil::open_zip(testutil::stored_zip(&[("a.txt", b"contents of a")]));
+        assert_eq!(zip.read_file_to_vec("a.txt").unwrap().as_slice(), b"contents of a");
+        assert_eq!(zip.read_file_to_vec("missing.txt").unwrap_err().kind, io::FileNotFound);
+    }
+
+    #[test]
+    fn zip_at_offset_is_read()
+    {
+        let mut data = Vec::from_elem(100, 0xAAu8);
+        data.push_all(testutil::stored_zip(&[("embedded.txt", b"embedded")]).as_slice());
+
+        let zip = super::ZipReader::new_with_offset(io::MemReader::new(data), 100).unwrap();
+        assert_eq!(zip.read_file_to_vec("embedded.txt").unwrap().as_slice(), b"embedded");
+    }
+
+    #[test]
+    fn zip_in_range_ignores_trailing_data()
+    {
+        let embedded = testutil::stored_zip(&[("embedded.txt", b"embedded")]);
+        let mut data = Vec::from_elem(100, 0xAAu8);
+        data.push_all(embedded.as_slice());
+        // Another ZIP file after the embedded one, whose end record comes last
+        data.push_all(testutil::stored_zip(&[("trailing.txt", b"trailing")]).as_slice());
+
+        let zip = super::ZipReader::new_with_range(io::MemReader::new(data), 100, embedded.len() as u64).unwrap();
+        assert_eq!(zip.len(), 1);
+        assert_eq!(zip.read_file_to_vec("embedded.txt").unwrap().as_slice(), b"embedded");
+    }
+
+    #[test]
+    fn strong_encryption_is_refused()
+    {
+        let mut data = testutil::stored_zip(&[("secret.txt", b"secret")]);
+        let central = testutil::positions(data.as_slice(), b"PK\x01\x02")[0];
+        testutil::put_le_u16(data.as_mut_slice(), central + 8, 1 << 6);
+        let zip = testutil::open_zip(data);
+
+        let file = zip.by_name("secret.txt").unwrap();
+        assert_eq!(file.unsupported_feature, Some("Strong encryption"));
+        let error = zip.read_file(file).err().unwrap();
+        assert_eq!(error.detail, Some("Strong encryption".to_string()));
+    }
+
+    #[test]
+    fn independent_readers_in_threads()
+    {
+        let first = Vec::from_elem(10000, b'1');
+        let second = Vec::from_elem(20000, b'2');
+        let data = testutil::write_zip(|zip|
+        {
+            try!(zip.start_file("first.txt", compression::Deflated));
+            try!(zip.write(first.as_slice()));
+            try!(zip.start_file("second.txt", compression::Deflated));
+            zip.write(second.as_slice())
+        });
+        let zip = super::ZipReader::new(::util::SharedMemReader::new(data)).unwrap();
+
+        let (tx, rx) = channel();
+        for name in ["first.txt", "second.txt"].iter()
+        {
+            let mut reader = zip.read_file_independent(zip.by_name(*name).unwrap()).unwrap();
+            let tx = tx.clone();
+            let name = name.to_string();
+            spawn(proc()
+            {
+                tx.send((name, reader.read_to_end().unwrap()));
+            });
+        }
+
+        let mut results = vec![rx.recv(), rx.recv()];
+        results.sort();
+        assert_eq!(results, vec![("first.txt".to_string(), first), ("second.txt".to_string(), second)]);
+    }
+
+    #[test]
+    fn remaining_counts_down()
+    {
+        let zip = testutil::open_zip(testutil::stored_zip(&[("a.txt", b"0123456789")]));
+        let mut raw_reader = zip.read_raw_file(zip.by_name("a.txt").unwrap()).unwrap();
+        assert_eq!(raw_reader.remaining(), 10);
+
+        let mut buf = [0u8, ..4];
+        assert_eq!(raw_reader.read(&mut buf).unwrap(), 4);
+        assert_eq!(raw_reader.remaining(), 6);
+        raw_reader.read_to_end().unwrap();
+        assert_eq!(raw_reader.remaining(), 0);
+    }
+
+    #[test]
+    fn oversized_compressed_size_is_not_allocated()
+    {
+        let mut data = testutil::stored_zip(&[("a.txt", b"a")]);
+        let central = testutil::positions(data.as_slice(), b"PK\x01\x02")[0];
+        // Claim almost 4 GiB of data, without the ZIP64 sentinel
+        testutil::put_le_u16(data.as_mut_slice(), central + 20, 0xFFF0);
+        testutil::put_le_u16(data.as_mut_slice(), central + 22, 0xFFFF);
+        let zip = testutil::open_zip(data);
+
+        let error = zip.raw_entries().next().unwrap().err().unwrap();
+        assert_eq!(error.desc, "Declared length extends beyond the end of the file");
+    }
+
+    #[test]
+    fn decompression_stops_at_size_limit()
+    {
+        let bomb = Vec::from_elem(1 << 20, 0u8);
+        let mut data = testutil::write_zip(|zip|
+        {
+            try!(zip.start_file("bomb.bin", compression::Deflated));
+            zip.write(bomb.as_slice())
+        });
+        // Declare a harmless uncompressed size, which must not be trusted
+        let central = testutil::positions(data.as_slice(), b"PK\x01\x02")[0];
+        testutil::put_le_u16(data.as_mut_slice(), central + 24, 100);
+        testutil::put_le_u16(data.as_mut_slice(), central + 26, 0);
+        let mut zip = testutil::open_zip(data);
+        zip.set_max_uncompressed_size(4096);
+
+        let error = zip.read_file_to_vec("bomb.bin").unwrap_err();
+        assert_eq!(error.desc, "Maximum uncompressed size exceeded");
+    }
+
+    #[test]
+    fn single_disk_archive_reports_disk_zero()
+    {
+        let zip = testutil::open_zip(testutil::stored_zip(&[("a.txt", b"a"), ("b.txt", b"b")]));
+        assert!(zip.files().all(|file| file.disk_number_start == 0));
+    }
+
+    fn digest_of_zip(comment: &str) -> u32
+    {
+        let zip = testutil::open_zip(testutil::write_zip(|zip|
+        {
+            zip.set_reproducible(time::strptime("2014-01-01 00:00:00", "%Y-%m-%d %H:%M:%S").unwrap());
+            try!(zip.start_file("a.txt", compression::Stored));
+            try!(zip.write(b"a"));
+            zip.set_file_comment(comment)
+        }));
+        zip.central_directory_digest().unwrap()
+    }
+
+    #[test]
+    fn central_directory_digest_compares_entries()
+    {
+        assert_eq!(digest_of_zip("same"), digest_of_zip("same"));
+        assert!(digest_of_zip("same") != digest_of_zip("different"));
+    }
+
+    #[test]
+    fn padding_after_end_record_needs_tolerance()
+    {
+        let mut data = testutil::stored_zip(&[("a.txt", b"a")]);
+        data.push_all(Vec::from_elem(16, 0u8).as_slice());
+        assert!(super::ZipReader::new(io::MemReader::new(data.clone())).is_err());
+
+        let zip = super::ZipReader::new_tolerant(io::MemReader::new(data)).unwrap();
+        assert_eq!(zip.read_file_to_vec("a.txt").unwrap().as_slice(), b"a");
+    }
+
+    #[test]
+    fn encrypted_file_needs_password()
+    {
+        let mut data = testutil::stored_zip(&[("secret.txt", b"secret")]);
+        let central = testutil::positions(data.as_slice(), b"PK\x01\x02")[0];
+        testutil::put_le_u16(data.as_mut_slice(), central + 8, 1);
+        let zip = testutil::open_zip(data);
+
+        let error = zip.read_file_to_vec("secret.txt").unwrap_err();
+        assert_eq!(error.detail, Some("Encrypted files can only be read with read_encrypted_to_vec".to_string()));
+    }
+
+    #[test]
+    fn encrypted_files_are_decrypted_with_password()
+    {
+        // Made by Info-ZIP with `zip -P secret`: stored.txt Stored, deflated.txt Deflated, both with data descriptors
+        let zip = testutil::open_zip(include_bin!("../test_data/zipcrypto.zip").to_vec());
+        assert!(zip.files().all(|file| file.encrypted));
+
+        assert_eq!(zip.read_encrypted_to_vec("stored.txt", b"secret").unwrap().as_slice(), b"Hello, encrypted World!\n");
+        let deflated = zip.read_encrypted_to_vec("deflated.txt", b"secret").unwrap();
+        assert_eq!(deflated.len(), 360);
+        assert!(deflated.as_slice().starts_with(b"compressible text compressible text "));
+
+        let error = zip.read_encrypted_to_vec("stored.txt", b"wrong").unwrap_err();
+        assert_eq!(error.kind, io::InvalidInput);
+        assert_eq!(error.desc, "Invalid password");
+        assert_eq!(zip.read_encrypted_to_vec("missing.txt", b"secret").unwrap_err().kind, io::FileNotFound);
+    }
+
+    #[test]
+    fn total_uncompressed_size_sums_files()
+    {
+        let zip = testutil::open_zip(testutil::stored_zip(&[("a.txt", b"aaa"), ("b.txt", b"bbbbb")]));
+        assert_eq!(zip.total_uncompressed_size(), Some(8));
+
+        let data = data_descriptor_zip(&[("a.txt", b"aaa")], true, false);
+        let zip = testutil::open_zip(data);
+        assert_eq!(zip.total_uncompressed_size(), None);
+    }
+
+    #[test]
+    fn empty_zip_is_empty()
+    {
+        let zip = testutil::open_zip(testutil::write_zip(|_| Ok(())));
+        assert!(zip.is_empty());
+        assert_eq!(zip.len(), 0);
+
+        let zip = testutil::open_zip(testutil::stored_zip(&[("a.txt", b"a"), ("b.txt", b"b")]));
+        assert!(!zip.is_empty());
+        assert_eq!(zip.len(), 2);
+    }
+
+    #[test]
+    fn range_is_read_from_middle_of_stored_file()
+    {
+        let zip = testutil::open_zip(testutil::stored_zip(&[("a.txt", b"0123456789")]));
+        let file = zip.by_name("a.txt").unwrap().clone();
+
+        assert_eq!(zip.read_range(&file, 3, 4).unwrap(), b"3456".to_vec());
+        assert_eq!(zip.read_range(&file, 10, 0).unwrap(), Vec::new());
+        assert_eq!(zip.read_range(&file, 8, 3).unwrap_err().desc, "Range exceeds the size of the file");
+    }
+
+    #[test]
+    fn checked_reader_reports_checksum_outcome()
+    {
+        let data = testutil::stored_zip(&[("a.txt", b"hello")]);
+        let zip = testutil::open_zip(data.clone());
+        let file = zip.by_name("a.txt").unwrap().clone();
+        {
+            let mut reader = zip.read_file_checked(&file).unwrap();
+            assert_eq!(reader.crc_ok(), None);
+            assert_eq!(reader.read_to_end().unwrap(), b"hello".to_vec());
+            assert_eq!(reader.crc_ok(), Some(true));
+        }
+
+        let mut corrupted = data;
+        let position = testutil::positions(corrupted.as_slice(), b"hello")[0];
+        corrupted[position] = b'j';
+        let zip = testutil::open_zip(corrupted);
+        let mut reader = zip.read_file_checked(&file).unwrap();
+        assert!(reader.read_to_end().is_err());
+        assert_eq!(reader.crc_ok(), Some(false));
+    }
+
+    #[test]
+    fn compression_methods_are_listed_once_in_order()
+    {
+        let zip = testutil::open_zip(testutil::write_zip(|zip|
+        {
+            try!(zip.start_file("a.txt", compression::Deflated));
+            try!(zip.write(b"aaaa"));
+            try!(zip.start_file("b.txt", compression::Stored));
+            try!(zip.write(b"bbbb"));
+            try!(zip.start_file("c.txt", compression::Deflated));
+            zip.write(b"cccc")
+        }));
+        assert_eq!(zip.compression_methods(), vec![compression::Deflated, compression::Stored]);
+    }
+
+    #[test]
+    fn wrong_comment_length_needs_tolerance()
+    {
+        let data = testutil::write_zip(|zip|
+        {
+            try!(zip.set_comment("hello"));
+            try!(zip.start_file("a.txt", compression::Stored));
+            zip.write(b"a")
+        });
+        let end = testutil::positions(data.as_slice(), b"PK\x05\x06")[0];
+
+        for &length in [2u16, 50].iter()
+        {
+            let mut damaged = data.clone();
+            testutil::put_le_u16(damaged.as_mut_slice(), end + 20, length);
+            assert!(super::ZipReader::new(io::MemReader::new(damaged.clone())).is_err());
+
+            let zip = super::ZipReader::new_tolerant(io::MemReader::new(damaged)).unwrap();
+            assert_eq!(zip.read_file_to_vec("a.txt").unwrap().as_slice(), b"a");
+        }
+    }
+
+    #[test]
+    fn file_is_read_into_stack_buffer()
+    {
+        let zip = testutil::open_zip(testutil::write_zip(|zip|
+        {
+            try!(zip.start_file("a.txt", compression::Deflated));
+            zip.write(b"hello hello hello")
+        }));
+
+        let mut buf = [0u8, ..32];
+        let len = zip.read_file_into("a.txt", &mut buf).unwrap();
+        assert_eq!(buf.slice_to(len), b"hello hello hello");
+
+        let mut small = [0u8, ..4];
+        assert_eq!(zip.read_file_into("a.txt", &mut small).unwrap_err().desc, "Buffer is smaller than the uncompressed size");
+        assert_eq!(zip.read_file_into("missing.txt", &mut buf).unwrap_err().kind, io::FileNotFound);
+    }
+
+    #[test]
+    fn entries_sharing_data_overlap()
+    {
+        let data = testutil::stored_zip(&[("a.txt", b"aaaa"), ("b.txt", b"bbbb")]);
+        assert!(!testutil::open_zip(data.clone()).has_overlapping_entries());
+
+        // Point the second central header at the first local header
+        let mut crafted = data;
+        let central = testutil::positions(crafted.as_slice(), b"PK\x01\x02")[1];
+        testutil::put_le_u16(crafted.as_mut_slice(), central + 42, 0);
+        testutil::put_le_u16(crafted.as_mut_slice(), central + 44, 0);
+        let zip = testutil::open_zip(crafted);
+        assert!(zip.has_overlapping_entries());
+    }
+
+    #[test]
+    fn file_count_beyond_16_bits_is_read_from_zip64_end_record()
+    {
+        static COUNT: uint = 70000;
+        let template = testutil::stored_zip(&[("f00000", b"")]);
+        let central = testutil::positions(template.as_slice(), b"PK\x01\x02")[0];
+        let end = testutil::positions(template.as_slice(), b"PK\x05\x06")[0];
+
+        // All central headers share the empty local header at offset 0
+        let mut writer = io::MemWriter::new();
+        writer.write(template.slice_to(central)).unwrap();
+        let directory_start = central as u64;
+        for i in range(0, COUNT)
+        {
+            writer.write(template.slice(central, end - 6)).unwrap();
+            writer.write(format!("f{:05}", i).as_bytes()).unwrap();
+        }
+        let zip64_end_start = writer.tell().unwrap();
+        let directory_size = zip64_end_start - directory_start;
+
+        writer.write_le_u32(0x06064b50).unwrap();
+        writer.write_le_u64(44).unwrap();
+        writer.write_le_u16(45).unwrap();
+        writer.write_le_u16(45).unwrap();
+        writer.write_le_u32(0).unwrap();
+        writer.write_le_u32(0).unwrap();
+        writer.write_le_u64(COUNT as u64).unwrap();
+        writer.write_le_u64(COUNT as u64).unwrap();
+        writer.write_le_u64(directory_size).unwrap();
+        writer.write_le_u64(directory_start).unwrap();
+
+        writer.write_le_u32(0x07064b50).unwrap();
+        writer.write_le_u32(0).unwrap();
+        writer.write_le_u64(zip64_end_start).unwrap();
+        writer.write_le_u32(1).unwrap();
+
+        // The regular record marks the file counts as stored in the ZIP64 record
+        writer.write_le_u32(0x06054b50).unwrap();
+        writer.write_le_u16(0).unwrap();
+        writer.write_le_u16(0).unwrap();
+        writer.write_le_u16(0xFFFF).unwrap();
+        writer.write_le_u16(0xFFFF).unwrap();
+        writer.write_le_u32(directory_size as u32).unwrap();
+        writer.write_le_u32(directory_start as u32).unwrap();
+        writer.write_le_u16(0).unwrap();
+
+        let zip = testutil::open_zip(writer.unwrap());
+        assert_eq!(zip.len(), COUNT);
+        assert!(!zip.count_mismatch());
+        assert_eq!(zip.files().last().unwrap().file_name.as_slice(), "f69999");
+        assert_eq!(zip.by_name("f65536").unwrap().uncompressed_size, 0);
+    }
+
+    #[test]
+    fn central_directory_bytes_parse_back_into_files()
+    {
+        let zip = testutil::open_zip(testutil::stored_zip(&[("a.txt", b"aaa"), ("dir/b.txt", b"bb")]));
+        let directory = zip.central_directory_bytes().unwrap();
+
+        let mut position = 0;
+        for expected in zip.files()
+        {
+            let (file, consumed) = ::reader_spec::parse_central_header_from_slice(directory.slice_from(position)).unwrap();
+            assert_eq!(file.file_name, expected.file_name);
+            assert_eq!(file.crc32, expected.crc32);
+            assert_eq!(file.compressed_size, expected.compressed_size);
+            assert_eq!(file.header_start, expected.header_start);
+            position += consumed;
+        }
+        assert_eq!(position, directory.len());
+    }
+
+    #[test]
+    fn streamed_chunks_concatenate_to_contents()
+    {
+        let contents: Vec<u8> = range(0u, 200000).map(|i| (i * 7 % 251) as u8).collect();
+        let zip = testutil::open_zip(testutil::write_zip(|zip|
+        {
+            try!(zip.start_file("data.bin", compression::Deflated));
+            zip.write(contents.as_slice())
+        }));
+        let file = zip.by_name("data.bin").unwrap().clone();
+
+        let mut streamed = Vec::new();
+        let mut chunks = 0u;
+        zip.stream_entry(&file, |chunk| { streamed.push_all(chunk); chunks += 1; Ok(()) }).unwrap();
+        assert_eq!(streamed, contents);
+        assert!(chunks > 1);
+
+        let error = zip.stream_entry(&file, |_| Err(io::standard_error(io::BrokenPipe))).unwrap_err();
+        assert_eq!(error.kind, io::BrokenPipe);
+    }
+
+    #[test]
+    fn inner_reader_is_reclaimed()
+    {
+        let data = testutil::stored_zip(&[("a.txt", b"a")]);
+        let zip = testutil::open_zip(data.clone());
+        let file = zip.by_name("a.txt").unwrap().clone();
+
+        assert_eq!(zip.get_ref().unwrap().get_ref(), data.as_slice());
+        {
+            let _reader = zip.read_file(&file).unwrap();
+            assert_eq!(zip.get_ref().err().unwrap().kind, io::ResourceUnavailable);
+        }
+        assert!(zip.get_ref().is_ok());
+
+        let mut inner = zip.into_inner();
+        assert_eq!(inner.get_ref(), data.as_slice());
+        inner.seek(0, io::SeekSet).unwrap();
+        assert_eq!(inner.read_to_end().unwrap(), data);
+    }
+
+    #[test]
+    fn damaged_header_is_reported_by_files_lenient()
+    {
+        let mut data = testutil::stored_zip(&[("a.txt", b"a"), ("b.txt", b"b"), ("c.txt", b"c")]);
+        assert_eq!(testutil::open_zip(data.clone()).files_lenient().filter(|result| result.is_ok()).count(), 3);
+
+        // Point the second file at a local header far beyond the end
+        let central = testutil::positions(data.as_slice(), b"PK\x01\x02")[1];
+        testutil::put_le_u16(data.as_mut_slice(), central + 42, 0xFFFF);
+        assert!(super::ZipReader::new(io::MemReader::new(data.clone())).is_err());
+
+        let zip = super::ZipReader::new_tolerant(io::MemReader::new(data)).unwrap();
+        let names: Vec<String> = zip.files().map(|file| file.file_name.clone()).collect();
+        assert_eq!(names, vec!["a.txt".to_string(), "c.txt".to_string()]);
+
+        let results: Vec<io::IoResult<::types::ZipFile>> = zip.files_lenient().collect();
+        assert_eq!(results.len(), 3);
+        assert_eq!(results[0].as_ref().unwrap().file_name.as_slice(), "a.txt");
+        assert!(results[1].is_err());
+        assert_eq!(results[2].as_ref().unwrap().file_name.as_slice(), "c.txt");
+    }
+
+    #[test]
+    fn files_by_offset_follow_data_order()
+    {
+        let data = testutil::stored_zip(&[("a.txt", b"a"), ("b.txt", b"b"), ("c.txt", b"c")]);
+        let headers = testutil::positions(data.as_slice(), b"PK\x01\x02");
+        let end = testutil::positions(data.as_slice(), b"PK\x05\x06")[0];
+
+        // Central directory listing the files as c, a, b
+        let mut scrambled = data.slice_to(headers[0]).to_vec();
+        scrambled.push_all(data.slice(headers[2], end));
+        scrambled.push_all(data.slice(headers[0], headers[2]));
+        scrambled.push_all(data.slice_from(end));
+        let zip = testutil::open_zip(scrambled);
+
+        let listed: Vec<&str> = zip.files().map(|file| file.file_name.as_slice()).collect();
+        assert_eq!(listed, vec!["c.txt", "a.txt", "b.txt"]);
+        let by_offset: Vec<&str> = zip.files_by_offset().map(|file| file.file_name.as_slice()).collect();
+        assert_eq!(by_offset, vec!["a.txt", "b.txt", "c.txt"]);
+        assert_eq!(zip.read_file_to_vec("c.txt").unwrap().as_slice(), b"c");
+    }
+
+    #[test]
+    fn size_of_sums_matching_files()
+    {
+        let zip = testutil::open_zip(testutil::write_zip(|zip|
+        {
+            try!(zip.start_file("docs/a.txt", compression::Stored));
+            try!(zip.write(b"aaaa"));
+            try!(zip.start_file("docs/b.txt", compression::Deflated));
+            try!(zip.write(Vec::from_elem(1000, b'b').as_slice()));
+            try!(zip.start_file("src/c.rs", compression::Stored));
+            zip.write(b"cc")
+        }));
+
+        let b = zip.by_name("docs/b.txt").unwrap().compressed_size;
+        assert_eq!(zip.size_of(|file| file.file_name.as_slice().starts_with("docs/")), (4 + b, 1004));
+        assert_eq!(zip.size_of(|file| file.file_name.as_slice().starts_with("src/")), (2, 2));
+        assert_eq!(zip.size_of(|_| false), (0, 0));
+    }
+
+    #[test]
+    fn strict_validation_reports_each_violation()
+    {
+        let data = testutil::stored_zip(&[("a.txt", b"aaaa"), ("b.txt", b"bbbb")]);
+        let local = testutil::positions(data.as_slice(), b"PK\x03\x04")[1];
+        let central = testutil::positions(data.as_slice(), b"PK\x01\x02")[1];
+        assert!(testutil::open_zip(data.clone()).validate_strict().is_ok());
+
+        let violation = |offset: uint, value: u16|
+        {
+            let mut crafted = data.clone();
+            testutil::put_le_u16(crafted.as_mut_slice(), offset, value);
+            let error = testutil::open_zip(crafted).validate_strict().unwrap_err();
+            assert_eq!(error.detail, Some("b.txt".to_string()));
+            error.desc
+        };
+        assert_eq!(violation(local + 30, 0x2e63), "File name in local header differs from central header");
+        assert_eq!(violation(local + 6, 1 << 1), "Flags in local header differ from central header");
+        assert_eq!(violation(local + 14, 0), "CRC-32 in local header differs from central header");
+        assert_eq!(violation(local + 18, 3), "Sizes in local header differ from central header");
+        assert_eq!(violation(local + 22, 3), "Sizes in local header differ from central header");
+        assert_eq!(violation(central + 6, 0), "Version needed to extract is too low for the file");
+
+        // A second central header for the same local header, with all headers consistent
+        let first = testutil::positions(data.as_slice(), b"PK\x01\x02")[0];
+        let end = testutil::positions(data.as_slice(), b"PK\x05\x06")[0];
+        let mut crafted = data.slice_to(end).to_vec();
+        crafted.push_all(data.slice(first, central));
+        crafted.push_all(data.slice_from(end));
+        let end = end + central - first;
+        testutil::put_le_u16(crafted.as_mut_slice(), end + 8, 3);
+        testutil::put_le_u16(crafted.as_mut_slice(), end + 10, 3);
+        testutil::put_le_u16(crafted.as_mut_slice(), end + 12, (end - first) as u16);
+        let error = testutil::open_zip(crafted).validate_strict().unwrap_err();
+        assert_eq!(error.desc, "Files overlap each other");
+    }
+
+    #[test]
+    fn nested_zip_is_opened()
+    {
+        let inner = testutil::stored_zip(&[("inner.txt", b"inside")]);
+        let outer = testutil::open_zip(testutil::write_zip(|zip|
+        {
+            try!(zip.start_file("nested.zip", compression::Deflated));
+            try!(zip.write(inner.as_slice()));
+            try!(zip.start_file("plain.txt", compression::Stored));
+            zip.write(b"not a zip")
+        }));
+
+        let nested = outer.open_nested("nested.zip").unwrap();
+        assert_eq!(nested.len(), 1);
+        assert_eq!(nested.read_file_to_vec("inner.txt").unwrap().as_slice(), b"inside");
+        assert!(outer.open_nested("plain.txt").is_err());
+        assert_eq!(outer.open_nested("missing.zip").err().unwrap().kind, io::FileNotFound);
+    }
+
+    #[test]
+    fn header_lengths_beyond_limits_are_rejected()
+    {
+        let data = testutil::write_zip(|zip|
+        {
+            try!(zip.add_extra_field_to_next_file(0x9999, Vec::from_elem(100, 0u8).as_slice()));
+            try!(zip.start_file("name.txt", compression::Stored));
+            zip.write(b"data")
+        });
+        assert!(super::ZipReader::new(io::MemReader::new(data.clone())).is_ok());
+
+        let mut limits = ::reader_spec::HeaderLimits::new();
+        limits.set_max_entry_name_len(7);
+        let error = super::ZipReader::new_with_limits(io::MemReader::new(data.clone()), limits).err().unwrap();
+        assert_eq!(error.desc, "Declared file name length exceeds the limit");
+        assert_eq!(error.detail, Some("8 bytes".to_string()));
+
+        let mut limits = ::reader_spec::HeaderLimits::new();
+        limits.set_max_extra_field_len(99);
+        let error = super::ZipReader::new_with_limits(io::MemReader::new(data.clone()), limits).err().unwrap();
+        assert_eq!(error.desc, "Declared extra field length exceeds the limit");
+        assert_eq!(error.detail, Some("104 bytes".to_string()));
+
+        let mut limits = ::reader_spec::HeaderLimits::new();
+        limits.set_max_entry_name_len(8);
+        limits.set_max_extra_field_len(104);
+        let zip = super::ZipReader::new_with_limits(io::MemReader::new(data), limits).unwrap();
+        assert_eq!(zip.read_file_to_vec("name.txt").unwrap().as_slice(), b"data");
     }
 }
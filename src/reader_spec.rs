@@ -1,11 +1,161 @@
+//! Parsing of the file headers of a ZIP.
+
 use std::io;
 use std::io::{IoResult, IoError};
 use compression;
 use types::ZipFile;
 use spec;
 use util;
+use time;
+
+static ZIP64_SENTINEL: u64 = 0xFFFFFFFF;
+
+/// Upper bounds on the name and extra field lengths declared in headers.
+///
+/// They are checked before anything is allocated for a header. Declared lengths are also checked
+/// against the size of the input, so these limits guard against lengths that fit the input but
+/// that no sane ZIP file uses.
+#[deriving(Clone, Show)]
+pub struct HeaderLimits
+{
+    max_entry_name_len: uint,
+    max_extra_field_len: uint,
+}
+
+impl HeaderLimits
+{
+    /// Limits of 4096 bytes for names, the longest path on most systems, and 65535 bytes for
+    /// extra fields, the longest a header can declare.
+    pub fn new() -> HeaderLimits
+    {
+        HeaderLimits { max_entry_name_len: 4096, max_extra_field_len: ::std::u16::MAX as uint }
+    }
+
+    /// Reject headers declaring a file name longer than `len` bytes.
+    pub fn set_max_entry_name_len(&mut self, len: uint)
+    {
+        self.max_entry_name_len = len;
+    }
+
+    /// Reject headers declaring an extra field longer than `len` bytes.
+    pub fn set_max_extra_field_len(&mut self, len: uint)
+    {
+        self.max_extra_field_len = len;
+    }
+
+    fn check(&self, file_name_length: uint, extra_field_length: uint) -> IoResult<()>
+    {
+        if file_name_length > self.max_entry_name_len
+        {
+            return Err(IoError {
+                kind: io::MismatchedFileTypeForOperation,
+                desc: "Declared file name length exceeds the limit",
+                detail: Some(format!("{} bytes", file_name_length)) })
+        }
+        if extra_field_length > self.max_extra_field_len
+        {
+            return Err(IoError {
+                kind: io::MismatchedFileTypeForOperation,
+                desc: "Declared extra field length exceeds the limit",
+                detail: Some(format!("{} bytes", extra_field_length)) })
+        }
+        Ok(())
+    }
+}
+
+/// Checks whether a central directory header starts at the current position, without moving.
+pub fn at_central_header<R: Reader+Seek>(reader: &mut R) -> IoResult<bool>
+{
+    let signature = match reader.read_le_u32()
+    {
+        Ok(signature) => signature,
+        Err(ref e) if e.kind == io::EndOfFile => return Ok(false),
+        Err(e) => return Err(e),
+    };
+    try!(reader.seek(-4, io::SeekCur));
+    Ok(signature == spec::CENTRAL_DIRECTORY_HEADER_SIGNATURE)
+}
+
+/// Fails unless `len` bytes are left after the current position, without moving.
+///
+/// Lengths read from a header are checked with this before allocating, so a damaged header cannot
+/// cause an allocation larger than the file itself.
+pub fn check_available<R: Reader+Seek>(reader: &mut R, len: u64) -> IoResult<()>
+{
+    let position = try!(reader.tell());
+    try!(reader.seek(0, io::SeekEnd));
+    let end = try!(reader.tell());
+    try!(reader.seek(position as i64, io::SeekSet));
+    if position > end || len > end - position
+    {
+        return Err(IoError {
+            kind: io::MismatchedFileTypeForOperation,
+            desc: "Declared length extends beyond the end of the file",
+            detail: None })
+    }
+    Ok(())
+}
+
+/// Parses the central directory header at the current position, including its local header.
+///
+/// Offsets in the header are taken relative to `archive_offset`. The header must end before
+/// `directory_end`, and the lengths in both headers must be within `limits`. Afterwards, the
+/// reader is positioned after the central directory header.
+pub fn central_header_to_zip_file<R: Reader+Seek>(reader: &mut R, archive_offset: u64, directory_end: u64,
+                                                 limits: &HeaderLimits) -> IoResult<ZipFile>
+{
+    let mut result = try!(read_central_header(reader, archive_offset, directory_end, limits));
+
+    // Remember end of central header
+    let return_position = try!(reader.tell()) as i64;
+
+    // Parse local header
+    try!(reader.seek(result.header_start as i64, io::SeekSet));
+    let signature = try!(reader.read_le_u32());
+    if signature != spec::LOCAL_FILE_HEADER_SIGNATURE
+    {
+        return Err(IoError {
+            kind: io::MismatchedFileTypeForOperation,
+            desc: "Invalid local file header",
+            detail: None })
+    }
+
+    try!(reader.seek(22, io::SeekCur));
+    // The local extra field may differ from the central one, so only the local lengths locate the data
+    let file_name_length = try!(reader.read_le_u16()) as u64;
+    let extra_field_length = try!(reader.read_le_u16()) as u64;
+    try!(limits.check(file_name_length as uint, extra_field_length as uint));
+    let magic_and_header = 4 + 22 + 2 + 2;
+    result.data_start = result.header_start + magic_and_header + file_name_length + extra_field_length;
+
+    // The central extended timestamp only holds the modification time, the other times are local
+    if extra_field_length > 0
+    {
+        try!(reader.seek(file_name_length as i64, io::SeekCur));
+        try!(check_available(&mut *reader, extra_field_length));
+        let local_extra_field = try!(reader.read_exact(extra_field_length as uint));
+        try!(parse_local_extra_field(&mut result, local_extra_field.as_slice()));
+    }
+
+    // Go back after the central header
+    try!(reader.seek(return_position, io::SeekSet));
+
+    Ok(result)
+}
+
+/// Parses a single central directory header from a slice.
+///
+/// The local header is not read, so `data_start` is 0. The lengths are checked against the
+/// default `HeaderLimits`. Returns the file and the number of bytes the header occupies.
+pub fn parse_central_header_from_slice(data: &[u8]) -> IoResult<(ZipFile, uint)>
+{
+    let mut reader = io::BufReader::new(data);
+    let file = try!(read_central_header(&mut reader, 0, data.len() as u64, &HeaderLimits::new()));
+    let consumed = try!(reader.tell()) as uint;
+    Ok((file, consumed))
+}
 
-pub fn central_header_to_zip_file<R: Reader+Seek>(reader: &mut R) -> IoResult<ZipFile>
+fn read_central_header<R: Reader+Seek>(reader: &mut R, archive_offset: u64, directory_end: u64, limits: &HeaderLimits) -> IoResult<ZipFile>
 {
     // Parse central header
     let signature = try!(reader.read_le_u32());
@@ -17,11 +167,13 @@ pub fn central_header_to_zip_file<R: Reader+Seek>(reader: &mut R) -> IoResult<Zi
             detail: None })
     }
 
-    try!(reader.read_le_u16());
-    try!(reader.read_le_u16());
+    let version_made_by = try!(reader.read_le_u16());
+    let version_needed = try!(reader.read_le_u16());
     let flags = try!(reader.read_le_u16());
     let encrypted = flags & 1 == 1;
     let is_utf8 = flags & (1 << 11) != 0;
+    let using_data_descriptor = flags & (1 << 3) != 0;
+    let unsupported_feature = unsupported_feature(flags);
     let compression_method = try!(reader.read_le_u16());
     let last_mod_time = try!(reader.read_le_u16());
     let last_mod_date = try!(reader.read_le_u16());
@@ -31,10 +183,22 @@ pub fn central_header_to_zip_file<R: Reader+Seek>(reader: &mut R) -> IoResult<Zi
     let file_name_length = try!(reader.read_le_u16()) as uint;
     let extra_field_length = try!(reader.read_le_u16()) as uint;
     let file_comment_length = try!(reader.read_le_u16()) as uint;
-    try!(reader.read_le_u16());
-    try!(reader.read_le_u16());
-    try!(reader.read_le_u32());
-    let offset = try!(reader.read_le_u32()) as i64;
+    let disk_number_start = try!(reader.read_le_u16());
+    let internal_attributes = try!(reader.read_le_u16());
+    let external_attributes = try!(reader.read_le_u32());
+    let offset = try!(reader.read_le_u32()) as u64;
+
+    // Do not trust the lengths before allocating: they must fit in the central directory and be within the limits
+    let variable_length = (file_name_length + extra_field_length + file_comment_length) as u64;
+    if try!(reader.tell()) + variable_length > directory_end
+    {
+        return Err(IoError {
+            kind: io::MismatchedFileTypeForOperation,
+            desc: "Central directory header extends beyond the central directory",
+            detail: None })
+    }
+    try!(limits.check(file_name_length, extra_field_length));
+
     let file_name_raw = try!(reader.read_exact(file_name_length));
     let extra_field = try!(reader.read_exact(extra_field_length));
     let file_comment_raw  = try!(reader.read_exact(file_comment_length));
@@ -50,11 +214,56 @@ pub fn central_header_to_zip_file<R: Reader+Seek>(reader: &mut R) -> IoResult<Zi
         false => ::cp437::to_string(file_comment_raw.as_slice()),
     };
 
-    // Remember end of central header
-    let return_position = try!(reader.tell()) as i64;
+    // Construct the result
+    let mut result = ZipFile
+    {
+        encrypted: encrypted,
+        compression_method: FromPrimitive::from_u16(compression_method).unwrap_or(compression::Unknown),
+        last_modified_time: util::msdos_datetime_to_tm(last_mod_time, last_mod_date),
+        crc32: crc32,
+        compressed_size: compressed_size as u64,
+        uncompressed_size: uncompressed_size as u64,
+        file_name: file_name,
+        file_comment: file_comment,
+        header_start: offset,
+        data_start: 0,
+        internal_attributes: internal_attributes,
+        unsupported_feature: unsupported_feature,
+        version_made_by: version_made_by,
+        version_needed: version_needed,
+        external_attributes: external_attributes,
+        disk_number_start: disk_number_start,
+        zip64: false,
+        flags: flags,
+        using_data_descriptor: using_data_descriptor,
+        last_access_time: None,
+        creation_time: None,
+        extra_field: Vec::new(),
+    };
+
+    try!(parse_extra_field(&mut result, extra_field.as_slice()));
+
+    // The sentinel must have been replaced by the ZIP64 extra field, or seeking to it is nonsense
+    if result.header_start == ZIP64_SENTINEL
+    {
+        return Err(IoError {
+            kind: io::MismatchedFileTypeForOperation,
+            desc: "Local header offset is 0xFFFFFFFF, but there is no ZIP64 extra field",
+            detail: None })
+    }
+    result.header_start += archive_offset;
+
+    Ok(result)
+}
+
+/// Parses the local file header at the current position.
+///
+/// Information only present in the central directory, such as the file comment, is left empty.
+/// The lengths in the header must be within `limits`.
+pub fn local_header_to_zip_file<R: Reader+Seek>(reader: &mut R, limits: &HeaderLimits) -> IoResult<ZipFile>
+{
+    let header_start = try!(reader.tell());
 
-    // Parse local header
-    try!(reader.seek(offset, io::SeekSet));
     let signature = try!(reader.read_le_u32());
     if signature != spec::LOCAL_FILE_HEADER_SIGNATURE
     {
@@ -64,13 +273,33 @@ pub fn central_header_to_zip_file<R: Reader+Seek>(reader: &mut R) -> IoResult<Zi
             detail: None })
     }
 
-    try!(reader.seek(22, io::SeekCur));
-    let file_name_length = try!(reader.read_le_u16()) as u64;
-    let extra_field_length = try!(reader.read_le_u16()) as u64;
-    let magic_and_header = 4 + 22 + 2 + 2;
-    let data_start = offset as u64 + magic_and_header + file_name_length + extra_field_length;
+    let version_needed = try!(reader.read_le_u16());
+    let flags = try!(reader.read_le_u16());
+    let encrypted = flags & 1 == 1;
+    let is_utf8 = flags & (1 << 11) != 0;
+    let using_data_descriptor = flags & (1 << 3) != 0;
+    let unsupported_feature = unsupported_feature(flags);
+    let compression_method = try!(reader.read_le_u16());
+    let last_mod_time = try!(reader.read_le_u16());
+    let last_mod_date = try!(reader.read_le_u16());
+    let crc32 = try!(reader.read_le_u32());
+    let compressed_size = try!(reader.read_le_u32());
+    let uncompressed_size = try!(reader.read_le_u32());
+    let file_name_length = try!(reader.read_le_u16()) as uint;
+    let extra_field_length = try!(reader.read_le_u16()) as uint;
+    try!(check_available(&mut *reader, (file_name_length + extra_field_length) as u64));
+    try!(limits.check(file_name_length, extra_field_length));
+    let file_name_raw = try!(reader.read_exact(file_name_length));
+    let extra_field = try!(reader.read_exact(extra_field_length));
+
+    let file_name = match is_utf8
+    {
+        true => String::from_utf8_lossy(file_name_raw.as_slice()).into_string(),
+        false => ::cp437::to_string(file_name_raw.as_slice()),
+    };
+
+    let data_start = try!(reader.tell());
 
-    // Construct the result
     let mut result = ZipFile
     {
         encrypted: encrypted,
@@ -80,31 +309,293 @@ pub fn central_header_to_zip_file<R: Reader+Seek>(reader: &mut R) -> IoResult<Zi
         compressed_size: compressed_size as u64,
         uncompressed_size: uncompressed_size as u64,
         file_name: file_name,
-        file_comment: file_comment,
-        header_start: offset as u64,
+        file_comment: String::new(),
+        header_start: header_start,
         data_start: data_start,
+        internal_attributes: 0,
+        unsupported_feature: unsupported_feature,
+        version_made_by: 0,
+        version_needed: version_needed,
+        external_attributes: 0,
+        disk_number_start: 0,
+        zip64: false,
+        flags: flags,
+        using_data_descriptor: using_data_descriptor,
+        last_access_time: None,
+        creation_time: None,
+        extra_field: Vec::new(),
     };
 
     try!(parse_extra_field(&mut result, extra_field.as_slice()));
 
-    // Go back after the central header
-    try!(reader.seek(return_position, io::SeekSet));
-
     Ok(result)
 }
 
-fn parse_extra_field(_file: &mut ZipFile, data: &[u8]) -> IoResult<()>
+/// Searches the data of a file for its data descriptor, returning the checksum and sizes.
+///
+/// The data may extend up to `limit`, where the next file or the central directory starts. A
+/// descriptor is accepted at the first position that matches the compressed size it records. Both
+/// the forms with and without the optional signature, and with 4 or 8 byte (ZIP64) sizes, are
+/// recognized. Returns None when no descriptor is found before `limit`.
+pub fn find_data_descriptor<R: Reader+Seek>(reader: &mut R, data_start: u64, limit: u64) -> IoResult<Option<(u32, u64, u64)>>
+{
+    // Signature, checksum and two ZIP64 sizes
+    static MAX_DESCRIPTOR_SIZE : uint = 24;
+    static CHUNK_SIZE : u64 = 64 * 1024;
+    if limit <= data_start { return Ok(None) }
+
+    try!(reader.seek(data_start as i64, io::SeekSet));
+    // Bytes read so far that may still hold a descriptor, starting at offset buffer_start of the reader
+    let mut buffer = Vec::new();
+    let mut buffer_start = data_start;
+    let mut index = 0u;
+
+    loop
+    {
+        let position = buffer_start + index as u64;
+        let buffer_end = buffer_start + buffer.len() as u64;
+        if buffer_end < limit && buffer_end < position + MAX_DESCRIPTOR_SIZE as u64
+        {
+            // Drop the bytes that were searched, and read the next chunk behind the rest
+            buffer = buffer.slice_from(index).to_vec();
+            buffer_start = position;
+            index = 0;
+            let chunk = try!(reader.read_exact(::std::cmp::min(limit - buffer_end, CHUNK_SIZE) as uint));
+            buffer.push_all(chunk.as_slice());
+        }
+
+        let window = buffer.slice(index, ::std::cmp::min(index + MAX_DESCRIPTOR_SIZE, buffer.len()));
+        // The smallest descriptor has a checksum and two 4 byte sizes
+        if window.len() < 12 { return Ok(None) }
+
+        let candidates = data_descriptor_candidates(window, position - data_start);
+        // A ZIP64 descriptor also matches as a short one; prefer the form the next header follows
+        let best = candidates.iter().find(|&&(_, length)| position + length == limit).or(candidates.iter().next());
+        match best
+        {
+            Some(&(descriptor, _)) => return Ok(Some(descriptor)),
+            None => {},
+        }
+        index += 1;
+    }
+}
+
+/// The data descriptors at the start of `data` that record the given compressed size, with their lengths.
+fn data_descriptor_candidates(data: &[u8], compressed_size: u64) -> Vec<((u32, u64, u64), u64)>
+{
+    let (fields, signature_length) = match data.len() >= 4 && le_value(data.slice_to(4)) == spec::DATA_DESCRIPTOR_SIGNATURE as u64
+    {
+        true => (data.slice_from(4), 4),
+        false => (data, 0),
+    };
+    let mut candidates = Vec::new();
+    if fields.len() < 12 { return candidates }
+
+    let crc32 = le_value(fields.slice_to(4)) as u32;
+    if le_value(fields.slice(4, 8)) == compressed_size
+    {
+        candidates.push(((crc32, compressed_size, le_value(fields.slice(8, 12))), signature_length + 12));
+    }
+    if fields.len() >= 20 && le_value(fields.slice(4, 12)) == compressed_size
+    {
+        candidates.push(((crc32, compressed_size, le_value(fields.slice(12, 20))), signature_length + 20));
+    }
+    candidates
+}
+
+fn le_value(data: &[u8]) -> u64
+{
+    data.iter().rev().fold(0, |value, &byte| (value << 8) | byte as u64)
+}
+
+fn unsupported_feature(flags: u16) -> Option<&'static str>
+{
+    if flags & (1 << 5) != 0 { Some("Compressed patched data") }
+    else if flags & (1 << 6) != 0 { Some("Strong encryption") }
+    else { None }
+}
+
+fn parse_extra_field(file: &mut ZipFile, data: &[u8]) -> IoResult<()>
 {
     let mut reader = io::BufReader::new(data);
-    while !reader.eof()
+    // Stop when no complete block header is left, instead of failing on trailing padding
+    while try!(reader.tell()) as uint + 4 <= data.len()
     {
         let kind = try!(reader.read_le_u16());
         let len = try!(reader.read_le_u16());
         debug!("Parsing extra block {:04x}", kind);
+        if try!(reader.tell()) as uint + len as uint > data.len() { break }
         match kind
         {
-            _ => try!(reader.seek(len as i64, io::SeekCur)),
+            0x0001 => try!(parse_zip64_extra(file, try!(reader.read_exact(len as uint)).as_slice())),
+            0x5455 => try!(parse_extended_timestamp(file, try!(reader.read_exact(len as uint)).as_slice())),
+            _ =>
+            {
+                // Keep the block, so it survives copying the file
+                let mut block = io::MemWriter::new();
+                try!(block.write_le_u16(kind));
+                try!(block.write_le_u16(len));
+                try!(block.write(try!(reader.read_exact(len as uint)).as_slice()));
+                file.extra_field.push_all(block.get_ref());
+            },
         }
     }
     Ok(())
 }
+
+/// Takes the times from the extended timestamp in the local extra field, ignoring all other blocks.
+fn parse_local_extra_field(file: &mut ZipFile, data: &[u8]) -> IoResult<()>
+{
+    let mut reader = io::BufReader::new(data);
+    while try!(reader.tell()) as uint + 4 <= data.len()
+    {
+        let kind = try!(reader.read_le_u16());
+        let len = try!(reader.read_le_u16()) as uint;
+        if try!(reader.tell()) as uint + len > data.len() { break }
+        let block = try!(reader.read_exact(len));
+        if kind == 0x5455 { try!(parse_extended_timestamp(file, block.as_slice())); }
+    }
+    Ok(())
+}
+
+fn parse_zip64_extra(file: &mut ZipFile, data: &[u8]) -> IoResult<()>
+{
+    let mut reader = io::BufReader::new(data);
+    file.zip64 = true;
+
+    // Only the fields that are set to the sentinel in the header are present, in this order
+    let mut fields = [&mut file.uncompressed_size, &mut file.compressed_size, &mut file.header_start];
+    for field in fields.iter_mut()
+    {
+        if **field != ZIP64_SENTINEL { continue }
+        if try!(reader.tell()) as uint + 8 > data.len() { break }
+        **field = try!(reader.read_le_u64());
+    }
+    Ok(())
+}
+
+fn parse_extended_timestamp(file: &mut ZipFile, data: &[u8]) -> IoResult<()>
+{
+    if data.is_empty() { return Ok(()) }
+    let mut reader = io::BufReader::new(data);
+    let flags = try!(reader.read_u8());
+
+    // The times follow in the order of the flag bits, but may be omitted in the central directory
+    for bit in range(0u, 3)
+    {
+        if flags & (1 << bit) == 0 { continue }
+        if try!(reader.tell()) as uint + 4 > data.len() { break }
+
+        let seconds = try!(reader.read_le_i32()) as i64;
+        let tm = time::at(time::Timespec::new(seconds, 0));
+        match bit
+        {
+            1 => file.last_access_time = Some(tm),
+            2 => file.creation_time = Some(tm),
+            _ => {},
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test
+{
+    use std::io;
+    use testutil;
+
+    #[test]
+    fn oversized_name_length_in_archive_is_rejected()
+    {
+        let mut data = testutil::stored_zip(&[("a.txt", b"a")]);
+        let central = testutil::positions(data.as_slice(), b"PK\x01\x02")[0];
+        testutil::put_le_u16(data.as_mut_slice(), central + 28, 0xFFFF);
+
+        let error = ::reader::ZipReader::new(io::MemReader::new(data)).err().unwrap();
+        assert_eq!(error.desc, "Central directory header extends beyond the central directory");
+    }
+
+    #[test]
+    fn oversized_lengths_in_slice_are_rejected()
+    {
+        let data = testutil::stored_zip(&[("a.txt", b"a")]);
+        let central = testutil::positions(data.as_slice(), b"PK\x01\x02")[0];
+        // Only the fixed part and the name
+        let mut header = data.slice(central, central + 46 + 5).to_vec();
+        testutil::put_le_u16(header.as_mut_slice(), 28, 0xFFFF);
+        assert!(super::parse_central_header_from_slice(header.as_slice()).is_err());
+
+        testutil::put_le_u16(header.as_mut_slice(), 28, 5);
+        testutil::put_le_u16(header.as_mut_slice(), 30, 0xFFFF);
+        assert!(super::parse_central_header_from_slice(header.as_slice()).is_err());
+    }
+
+    #[test]
+    fn oversized_name_length_in_local_header_is_rejected()
+    {
+        let mut data = testutil::stored_zip(&[("a.txt", b"a")]);
+        testutil::put_le_u16(data.as_mut_slice(), 26, 0xFFFF);
+
+        let error = super::local_header_to_zip_file(&mut io::MemReader::new(data), &super::HeaderLimits::new()).err().unwrap();
+        assert_eq!(error.desc, "Declared length extends beyond the end of the file");
+    }
+
+    #[test]
+    fn central_header_is_parsed_from_slice()
+    {
+        // Made by Unix, Stored, dated 1980-01-01, mode 0o100644, local header at 16
+        let mut data = b"PK\x01\x02\x14\x03\x14\x00\x00\x00\x00\x00\x00\x00\x21\x00\x78\x56\x34\x12".to_vec();
+        data.push_all(b"\x05\x00\x00\x00\x05\x00\x00\x00\x09\x00\x00\x00\x00\x00\x00\x00\x00\x00");
+        data.push_all(b"\x00\x00\xa4\x81\x10\x00\x00\x00hello.txt");
+        // Bytes of the next header are not consumed
+        data.push_all(b"PK\x01\x02");
+
+        let (file, consumed) = super::parse_central_header_from_slice(data.as_slice()).unwrap();
+        assert_eq!(consumed, 46 + 9);
+        assert_eq!(file.file_name.as_slice(), "hello.txt");
+        assert_eq!(file.crc32, 0x12345678);
+        assert_eq!(file.compressed_size, 5);
+        assert_eq!(file.uncompressed_size, 5);
+        assert_eq!(file.header_start, 16);
+        assert_eq!(file.unix_mode(), Some(0o100644));
+    }
+
+    #[test]
+    fn sentinel_offset_without_zip64_extra_field_is_rejected()
+    {
+        let mut data = testutil::stored_zip(&[("a.txt", b"a")]);
+        let central = testutil::positions(data.as_slice(), b"PK\x01\x02")[0];
+        testutil::put_le_u16(data.as_mut_slice(), central + 42, 0xFFFF);
+        testutil::put_le_u16(data.as_mut_slice(), central + 44, 0xFFFF);
+
+        let error = ::reader::ZipReader::new(io::MemReader::new(data)).err().unwrap();
+        assert_eq!(error.desc, "Local header offset is 0xFFFFFFFF, but there is no ZIP64 extra field");
+    }
+
+    #[test]
+    fn data_start_uses_local_extra_field_length()
+    {
+        let mut options = ::writer::FileOptions::new(::compression::Stored);
+        options.last_access_time = Some(::time::at(::time::Timespec::new(1400000000, 0)));
+        options.creation_time = Some(::time::at(::time::Timespec::new(1300000000, 0)));
+        let data = testutil::write_zip(|zip|
+        {
+            try!(zip.start_file_with_options("a.txt", options.clone()));
+            zip.write(b"payload")
+        });
+
+        // The local extended timestamp holds three times, the central one only one
+        let local = testutil::positions(data.as_slice(), b"PK\x03\x04")[0];
+        let central = testutil::positions(data.as_slice(), b"PK\x01\x02")[0];
+        let local_extra_length = data[local + 28] as u64 + (data[local + 29] as u64 << 8);
+        let central_extra_length = data[central + 30] as u64 + (data[central + 31] as u64 << 8);
+        assert!(local_extra_length > central_extra_length);
+
+        let zip = testutil::open_zip(data.clone());
+        let file = zip.by_name("a.txt").unwrap();
+        assert_eq!(file.data_start, local as u64 + 30 + 5 + local_extra_length);
+        let start = file.data_start as uint;
+        assert_eq!(data.slice(start, start + 7), b"payload");
+        assert_eq!(zip.read_file_to_vec("a.txt").unwrap(), b"payload".to_vec());
+    }
+}
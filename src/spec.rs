@@ -1,25 +1,48 @@
+//! Low-level structures of the ZIP format.
+
 use std::io;
 use std::io::{IoResult, IoError};
 use std::iter::range_step_inclusive;
 
+/// Signature of a local file header
 pub static LOCAL_FILE_HEADER_SIGNATURE : u32 = 0x04034b50;
+/// Signature optionally preceding a data descriptor
+pub static DATA_DESCRIPTOR_SIGNATURE : u32 = 0x08074b50;
+/// Signature of a central directory file header
 pub static CENTRAL_DIRECTORY_HEADER_SIGNATURE : u32 = 0x02014b50;
 static CENTRAL_DIRECTORY_END_SIGNATURE : u32 = 0x06054b50;
+static ZIP64_CENTRAL_DIRECTORY_END_SIGNATURE : u32 = 0x06064b50;
+static ZIP64_CENTRAL_DIRECTORY_END_LOCATOR_SIGNATURE : u32 = 0x07064b50;
 
+/// The end of central directory record, found at the end of every ZIP file.
 pub struct CentralDirectoryEnd
 {
+    /// Number of this disk
     pub disk_number: u16,
+    /// Number of the disk on which the central directory starts
     pub disk_with_central_directory: u16,
+    /// Number of central directory entries on this disk
     pub number_of_files_on_this_disk: u16,
+    /// Total number of central directory entries
     pub number_of_files: u16,
+    /// Size of the central directory in bytes
     pub central_directory_size: u32,
+    /// Offset of the start of the central directory
     pub central_directory_offset: u32,
+    /// Comment of the ZIP file
     pub zip_file_comment: Vec<u8>,
 }
 
 impl CentralDirectoryEnd
 {
+    /// Parse the record at the current position of the reader.
     pub fn parse<T: Reader>(reader: &mut T) -> IoResult<CentralDirectoryEnd>
+    {
+        CentralDirectoryEnd::parse_truncated(reader, ::std::uint::MAX)
+    }
+
+    /// Parse the record, reading at most `available` bytes of comment regardless of the declared length.
+    fn parse_truncated<T: Reader>(reader: &mut T, available: uint) -> IoResult<CentralDirectoryEnd>
     {
         let magic = try!(reader.read_le_u32());
         if magic != CENTRAL_DIRECTORY_END_SIGNATURE
@@ -36,7 +59,7 @@ impl CentralDirectoryEnd
         let central_directory_size = try!(reader.read_le_u32());
         let central_directory_offset = try!(reader.read_le_u32());
         let zip_file_comment_length = try!(reader.read_le_u16()) as uint;
-        let zip_file_comment = try!(reader.read_exact(zip_file_comment_length));
+        let zip_file_comment = try!(reader.read_exact(::std::cmp::min(zip_file_comment_length, available)));
 
         Ok(CentralDirectoryEnd
            {
@@ -50,14 +73,42 @@ impl CentralDirectoryEnd
            })
     }
 
+    /// Search backwards from the end of the reader for the record and parse it.
+    ///
+    /// A candidate is accepted when its comment reaches exactly to the end of the file.
     pub fn find_and_parse<T: Reader+Seek>(reader: &mut T) -> IoResult<CentralDirectoryEnd>
+    {
+        let end = try!(stream_length(reader));
+        CentralDirectoryEnd::find_and_parse_with(reader, 0, end, false)
+    }
+
+    /// Like `find_and_parse`, but for a ZIP file occupying the bytes from `start` up to `end` of the reader.
+    ///
+    /// The search starts at `end` instead of the end of the reader and does not go before `start`,
+    /// so data surrounding an embedded ZIP file, even another ZIP file, is ignored.
+    pub fn find_and_parse_in<T: Reader+Seek>(reader: &mut T, start: u64, end: u64) -> IoResult<CentralDirectoryEnd>
+    {
+        CentralDirectoryEnd::find_and_parse_with(reader, start, end, false)
+    }
+
+    /// Like `find_and_parse`, but accepts a record regardless of its comment length.
+    ///
+    /// Some tools pad ZIP files to a block boundary, and damaged files may declare a comment longer
+    /// than the remaining bytes, which is then cut off at the end of the file. As the search still
+    /// starts at the end of the file, the last record is found.
+    pub fn find_and_parse_tolerant<T: Reader+Seek>(reader: &mut T) -> IoResult<CentralDirectoryEnd>
+    {
+        let end = try!(stream_length(reader));
+        CentralDirectoryEnd::find_and_parse_with(reader, 0, end, true)
+    }
+
+    fn find_and_parse_with<T: Reader+Seek>(reader: &mut T, start: u64, end: u64, tolerant: bool) -> IoResult<CentralDirectoryEnd>
     {
         let header_size = 22;
         let bytes_between_magic_and_comment_size = header_size - 6;
-        try!(reader.seek(0, io::SeekEnd));
-        let file_length = try!(reader.tell()) as i64;
+        let file_length = end as i64;
 
-        let search_upper_bound = ::std::cmp::max(0, file_length - header_size - ::std::u16::MAX as i64);
+        let search_upper_bound = ::std::cmp::max(start as i64, file_length - header_size - ::std::u16::MAX as i64);
         for pos in range_step_inclusive(file_length - header_size, search_upper_bound, -1)
         {
             try!(reader.seek(pos, io::SeekSet));
@@ -65,10 +116,11 @@ impl CentralDirectoryEnd
             {
                 try!(reader.seek(bytes_between_magic_and_comment_size, io::SeekCur));
                 let comment_length = try!(reader.read_le_u16()) as i64;
-                if file_length - pos - header_size == comment_length
+                let trailing_length = file_length - pos - header_size;
+                if trailing_length == comment_length || tolerant
                 {
                     try!(reader.seek(pos, io::SeekSet));
-                    return CentralDirectoryEnd::parse(reader);
+                    return CentralDirectoryEnd::parse_truncated(reader, trailing_length as uint);
                 }
             }
         }
@@ -80,6 +132,7 @@ impl CentralDirectoryEnd
             })
     }
 
+    /// Write the record to the writer.
     pub fn write<T: Writer>(&self, writer: &mut T) -> IoResult<()>
     {
         try!(writer.write_le_u32(CENTRAL_DIRECTORY_END_SIGNATURE));
@@ -94,3 +147,219 @@ impl CentralDirectoryEnd
         Ok(())
     }
 }
+
+fn stream_length<T: Seek>(reader: &mut T) -> IoResult<u64>
+{
+    try!(reader.seek(0, io::SeekEnd));
+    reader.tell()
+}
+
+/// Cheaply checks whether the reader looks like a ZIP file.
+///
+/// This looks for the end of central directory record and, if the ZIP file is not empty, checks the
+/// signatures of the first central directory header and of the local header it points to. Nothing
+/// else is parsed. Data before the ZIP file, such as the stub of a self-extracting archive, is
+/// allowed.
+pub fn is_valid_zip<T: Reader+Seek>(reader: &mut T) -> bool
+{
+    match check_first_headers(reader)
+    {
+        Ok(valid) => valid,
+        Err(_) => false,
+    }
+}
+
+fn check_first_headers<T: Reader+Seek>(reader: &mut T) -> IoResult<bool>
+{
+    static LOCAL_HEADER_OFFSET_POSITION : i64 = 42;
+
+    let footer = try!(CentralDirectoryEnd::find_and_parse(reader));
+    if footer.number_of_files == 0 { return Ok(true) }
+    // The offsets of large ZIP files are only in the ZIP64 record, which is not looked up here
+    if footer.central_directory_offset == 0xFFFFFFFF { return Ok(true) }
+
+    let directory_start = footer.central_directory_offset as i64;
+    try!(reader.seek(directory_start, io::SeekSet));
+    if try!(reader.read_le_u32()) != CENTRAL_DIRECTORY_HEADER_SIGNATURE { return Ok(false) }
+
+    try!(reader.seek(directory_start + LOCAL_HEADER_OFFSET_POSITION, io::SeekSet));
+    let local_header_offset = try!(reader.read_le_u32());
+    if local_header_offset == 0xFFFFFFFF { return Ok(true) }
+    try!(reader.seek(local_header_offset as i64, io::SeekSet));
+    Ok(try!(reader.read_le_u32()) == LOCAL_FILE_HEADER_SIGNATURE)
+}
+
+/// The ZIP64 end of central directory record, holding the values that do not fit in the regular record.
+pub struct Zip64CentralDirectoryEnd
+{
+    /// Version and host system that made the ZIP file
+    pub version_made_by: u16,
+    /// Minimum version needed to extract the ZIP file
+    pub version_needed: u16,
+    /// Number of this disk
+    pub disk_number: u32,
+    /// Number of the disk on which the central directory starts
+    pub disk_with_central_directory: u32,
+    /// Number of central directory entries on this disk
+    pub number_of_files_on_this_disk: u64,
+    /// Total number of central directory entries
+    pub number_of_files: u64,
+    /// Size of the central directory in bytes
+    pub central_directory_size: u64,
+    /// Offset of the start of the central directory
+    pub central_directory_offset: u64,
+}
+
+impl Zip64CentralDirectoryEnd
+{
+    /// Parse the record at the current position of the reader.
+    pub fn parse<T: Reader>(reader: &mut T) -> IoResult<Zip64CentralDirectoryEnd>
+    {
+        let magic = try!(reader.read_le_u32());
+        if magic != ZIP64_CENTRAL_DIRECTORY_END_SIGNATURE
+        {
+            return Err(IoError {
+                kind: io::MismatchedFileTypeForOperation,
+                desc: "Invalid ZIP64 end of central directory record",
+                detail: None })
+        }
+        let _record_size = try!(reader.read_le_u64());
+        let version_made_by = try!(reader.read_le_u16());
+        let version_needed = try!(reader.read_le_u16());
+        let disk_number = try!(reader.read_le_u32());
+        let disk_with_central_directory = try!(reader.read_le_u32());
+        let number_of_files_on_this_disk = try!(reader.read_le_u64());
+        let number_of_files = try!(reader.read_le_u64());
+        let central_directory_size = try!(reader.read_le_u64());
+        let central_directory_offset = try!(reader.read_le_u64());
+
+        Ok(Zip64CentralDirectoryEnd
+           {
+               version_made_by: version_made_by,
+               version_needed: version_needed,
+               disk_number: disk_number,
+               disk_with_central_directory: disk_with_central_directory,
+               number_of_files_on_this_disk: number_of_files_on_this_disk,
+               number_of_files: number_of_files,
+               central_directory_size: central_directory_size,
+               central_directory_offset: central_directory_offset,
+           })
+    }
+
+    /// Find the record through the locator preceding the end of central directory record at `end_position`.
+    ///
+    /// The offset in the locator is taken relative to `archive_offset`. Returns None when there is
+    /// no locator.
+    pub fn find_and_parse<T: Reader+Seek>(reader: &mut T, end_position: u64, archive_offset: u64) -> IoResult<Option<Zip64CentralDirectoryEnd>>
+    {
+        static LOCATOR_SIZE : u64 = 20;
+        if end_position < LOCATOR_SIZE { return Ok(None) }
+
+        try!(reader.seek((end_position - LOCATOR_SIZE) as i64, io::SeekSet));
+        if try!(reader.read_le_u32()) != ZIP64_CENTRAL_DIRECTORY_END_LOCATOR_SIGNATURE { return Ok(None) }
+        let _disk_with_end = try!(reader.read_le_u32());
+        let end_offset = try!(reader.read_le_u64());
+        let _number_of_disks = try!(reader.read_le_u32());
+
+        try!(reader.seek((end_offset + archive_offset) as i64, io::SeekSet));
+        Ok(Some(try!(Zip64CentralDirectoryEnd::parse(reader))))
+    }
+
+    /// Write the record to the writer.
+    pub fn write<T: Writer>(&self, writer: &mut T) -> IoResult<()>
+    {
+        // Size of the remaining record, without the signature and this field
+        static RECORD_SIZE : u64 = 44;
+        try!(writer.write_le_u32(ZIP64_CENTRAL_DIRECTORY_END_SIGNATURE));
+        try!(writer.write_le_u64(RECORD_SIZE));
+        try!(writer.write_le_u16(self.version_made_by));
+        try!(writer.write_le_u16(self.version_needed));
+        try!(writer.write_le_u32(self.disk_number));
+        try!(writer.write_le_u32(self.disk_with_central_directory));
+        try!(writer.write_le_u64(self.number_of_files_on_this_disk));
+        try!(writer.write_le_u64(self.number_of_files));
+        try!(writer.write_le_u64(self.central_directory_size));
+        try!(writer.write_le_u64(self.central_directory_offset));
+        Ok(())
+    }
+
+    /// Write the locator pointing to a record at `end_offset`, which goes right before the regular record.
+    pub fn write_locator<T: Writer>(writer: &mut T, end_offset: u64) -> IoResult<()>
+    {
+        try!(writer.write_le_u32(ZIP64_CENTRAL_DIRECTORY_END_LOCATOR_SIGNATURE));
+        try!(writer.write_le_u32(0));
+        try!(writer.write_le_u64(end_offset));
+        try!(writer.write_le_u32(1));
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test
+{
+    use std::io;
+    use compression;
+    use testutil;
+    use writer::ZipWriter;
+
+    #[test]
+    fn central_directory_end_round_trip()
+    {
+        let footer = super::CentralDirectoryEnd
+        {
+            disk_number: 0,
+            disk_with_central_directory: 0,
+            number_of_files_on_this_disk: 3,
+            number_of_files: 3,
+            central_directory_size: 150,
+            central_directory_offset: 1000,
+            zip_file_comment: b"a comment".to_vec(),
+        };
+        let mut writer = io::MemWriter::new();
+        footer.write(&mut writer).unwrap();
+
+        let mut reader = io::MemReader::new(writer.unwrap());
+        let parsed = super::CentralDirectoryEnd::find_and_parse(&mut reader).unwrap();
+        assert_eq!(parsed.number_of_files_on_this_disk, 3);
+        assert_eq!(parsed.number_of_files, 3);
+        assert_eq!(parsed.central_directory_size, 150);
+        assert_eq!(parsed.central_directory_offset, 1000);
+        assert_eq!(parsed.zip_file_comment, footer.zip_file_comment);
+    }
+
+    #[test]
+    fn valid_zip_is_recognized()
+    {
+        let data = testutil::stored_zip(&[("a.txt", b"a")]);
+        assert!(super::is_valid_zip(&mut io::MemReader::new(data)));
+    }
+
+    #[test]
+    fn truncated_zip_is_rejected()
+    {
+        let mut data = testutil::stored_zip(&[("a.txt", b"a")]);
+        let len = data.len();
+        data.truncate(len - 10);
+        assert!(!super::is_valid_zip(&mut io::MemReader::new(data)));
+    }
+
+    #[test]
+    fn other_file_is_rejected()
+    {
+        let data = b"This is a plain text file, not a ZIP file.".to_vec();
+        assert!(!super::is_valid_zip(&mut io::MemReader::new(data)));
+    }
+
+    #[test]
+    fn zip_after_stub_is_recognized()
+    {
+        let mut sink = testutil::MemSink::new();
+        sink.write(Vec::from_elem(500, 0x90u8).as_slice()).unwrap();
+        let mut zip = ZipWriter::new(sink);
+        zip.start_file("a.txt", compression::Stored).unwrap();
+        zip.write(b"a").unwrap();
+        let data = zip.finish().unwrap().into_inner();
+
+        assert!(super::is_valid_zip(&mut io::MemReader::new(data)));
+    }
+}
@@ -0,0 +1,149 @@
+//! Helpers for building and inspecting ZIP files in the tests.
+
+use std::cell::RefCell;
+use std::io;
+use std::io::IoResult;
+use std::rc::Rc;
+use compression;
+use reader::ZipReader;
+use types::ZipFile;
+use writer::ZipWriter;
+
+/// Writer that keeps all data in memory and supports seeking.
+pub struct MemSink
+{
+    data: Vec<u8>,
+    position: uint,
+    seekable: bool,
+    flushed: Rc<RefCell<Vec<u8>>>,
+}
+
+impl MemSink
+{
+    pub fn new() -> MemSink
+    {
+        MemSink { data: Vec::new(), position: 0, seekable: true, flushed: Rc::new(RefCell::new(Vec::new())), }
+    }
+
+    /// A sink on which seeking fails, to check that nothing is written out of order.
+    pub fn without_seek() -> MemSink
+    {
+        MemSink { data: Vec::new(), position: 0, seekable: false, flushed: Rc::new(RefCell::new(Vec::new())), }
+    }
+
+    /// The contents as of the last flush, which stays readable while a ZipWriter owns the sink.
+    pub fn flushed(&self) -> Rc<RefCell<Vec<u8>>>
+    {
+        self.flushed.clone()
+    }
+
+    pub fn into_inner(self) -> Vec<u8>
+    {
+        self.data
+    }
+}
+
+impl Writer for MemSink
+{
+    fn write(&mut self, buf: &[u8]) -> IoResult<()>
+    {
+        let end = self.position + buf.len();
+        if self.data.len() < end
+        {
+            let missing = end - self.data.len();
+            self.data.grow(missing, 0u8);
+        }
+        ::std::slice::bytes::copy_memory(self.data.slice_mut(self.position, end), buf);
+        self.position = end;
+        Ok(())
+    }
+
+    fn flush(&mut self) -> IoResult<()>
+    {
+        *self.flushed.borrow_mut() = self.data.clone();
+        Ok(())
+    }
+}
+
+impl Seek for MemSink
+{
+    fn tell(&self) -> IoResult<u64>
+    {
+        Ok(self.position as u64)
+    }
+
+    fn seek(&mut self, pos: i64, style: io::SeekStyle) -> IoResult<()>
+    {
+        if !self.seekable
+        {
+            return Err(io::standard_error(io::OtherIoError))
+        }
+        let base = match style
+        {
+            io::SeekSet => 0,
+            io::SeekCur => self.position as i64,
+            io::SeekEnd => self.data.len() as i64,
+        };
+        if base + pos < 0
+        {
+            return Err(io::standard_error(io::InvalidInput))
+        }
+        self.position = (base + pos) as uint;
+        Ok(())
+    }
+}
+
+/// Write a ZIP file with `f`, and return its bytes.
+pub fn write_zip(f: |&mut ZipWriter<MemSink>| -> IoResult<()>) -> Vec<u8>
+{
+    let mut zip = ZipWriter::new(MemSink::new());
+    f(&mut zip).unwrap();
+    zip.finish().unwrap().into_inner()
+}
+
+/// ZIP file containing the given (name, contents) pairs as Stored files.
+pub fn stored_zip(files: &[(&str, &[u8])]) -> Vec<u8>
+{
+    write_zip(|zip|
+    {
+        for &(name, data) in files.iter()
+        {
+            try!(zip.start_file(name, compression::Stored));
+            try!(zip.write(data));
+        }
+        Ok(())
+    })
+}
+
+/// Open a ZIP file held in memory.
+pub fn open_zip(data: Vec<u8>) -> ZipReader<io::MemReader>
+{
+    ZipReader::new(io::MemReader::new(data)).unwrap()
+}
+
+/// Information of the only file of a ZIP file, written with the given contents.
+pub fn single_file(name: &str, method: compression::CompressionMethod, data: &[u8]) -> ZipFile
+{
+    let zip = open_zip(write_zip(|zip|
+    {
+        try!(zip.start_file(name, method));
+        zip.write(data)
+    }));
+    zip.files().next().unwrap().clone()
+}
+
+/// Offsets of all occurrences of `needle` in `data`, such as the signatures of a kind of header.
+pub fn positions(data: &[u8], needle: &[u8]) -> Vec<uint>
+{
+    data.windows(needle.len())
+        .enumerate()
+        .filter(|&(_, window)| window == needle)
+        .map(|(i, _)| i)
+        .collect()
+}
+
+pub fn put_le_u16(data: &mut [u8], offset: uint, value: u16)
+{
+    data[offset] = value as u8;
+    data[offset + 1] = (value >> 8) as u8;
+}
@@ -1,8 +1,22 @@
 //! Types that specify what is contained in a ZIP.
 
+use std::fmt;
 use time;
 
+/// System that made a file, which determines how its external attributes are interpreted.
+#[deriving(FromPrimitive, Clone, PartialEq, Show)]
+pub enum HostSystem
+{
+    /// MS-DOS and OS/2, with FAT attributes such as 0x01 for read-only
+    HostDos = 0,
+    /// Unix, with the mode in the upper 16 bits
+    HostUnix = 3,
+    /// Windows NTFS, with FAT attributes
+    HostNtfs = 11,
+}
+
 /// Structure representing a ZIP file.
+#[deriving(Clone)]
 pub struct ZipFile
 {
     /// True if the file is encrypted.
@@ -25,4 +39,335 @@ pub struct ZipFile
     pub header_start: u64,
     /// Specifies where the compressed data of the file starts
     pub data_start: u64,
+    /// Internal file attributes. Bit 0 indicates the file is apparently text.
+    pub internal_attributes: u16,
+    /// Set when the file uses a feature that prevents it from being read, such as strong encryption.
+    pub unsupported_feature: Option<&'static str>,
+    /// Version and host system that made the file. The upper byte is the host system.
+    pub version_made_by: u16,
+    /// Minimum version of the specification needed to extract the file
+    pub version_needed: u16,
+    /// External file attributes, interpreted according to the host system
+    pub external_attributes: u32,
+    /// Number of the disk on which the file starts
+    pub disk_number_start: u16,
+    /// True if the sizes of the file are stored in a ZIP64 extra field
+    pub zip64: bool,
+    /// General purpose bit flags, as read from the header
+    pub flags: u16,
+    /// True if the checksum and sizes are stored in a data descriptor after the data
+    pub using_data_descriptor: bool,
+    /// Last access time, from the extended timestamp extra field
+    pub last_access_time: Option<time::Tm>,
+    /// Creation time, from the extended timestamp extra field
+    pub creation_time: Option<time::Tm>,
+    /// Extra field blocks that are not interpreted by this crate, such as NTFS attributes.
+    ///
+    /// These are kept as they were read, including their headers, and written out again when the
+    /// file is copied.
+    pub extra_field: Vec<u8>,
+}
+
+/// Location of the compressed data of a file within the ZIP.
+#[deriving(Clone, PartialEq)]
+pub struct DataRange
+{
+    /// Offset of the first byte of the data
+    pub offset: u64,
+    /// Number of bytes of data
+    pub len: u64,
+}
+
+/// Summary of all files contained in a ZIP.
+pub struct ArchiveInfo
+{
+    /// Number of files
+    pub number_of_files: uint,
+    /// Sum of the sizes of all files in the ZIP
+    pub compressed_size: u64,
+    /// Sum of the sizes of all files when extracted
+    pub uncompressed_size: u64,
+    /// True if any of the files is encrypted
+    pub encrypted: bool,
+    /// The distinct compression methods used, in order of first appearance
+    pub compression_methods: Vec<::compression::CompressionMethod>,
+}
+
+impl fmt::Show for ZipFile
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
+    {
+        let tm = &self.last_modified_time;
+        write!(f, "{} ({}, {} -> {} bytes, modified {:04}-{:02}-{:02} {:02}:{:02}:{:02})",
+               self.file_name,
+               self.compression_method,
+               self.compressed_size,
+               self.uncompressed_size,
+               tm.tm_year + 1900,
+               tm.tm_mon + 1,
+               tm.tm_mday,
+               tm.tm_hour,
+               tm.tm_min,
+               tm.tm_sec)
+    }
+}
+
+impl ZipFile
+{
+    /// System that made the file, if it is one of the known systems.
+    pub fn host_system(&self) -> Option<HostSystem>
+    {
+        FromPrimitive::from_u16(self.version_made_by >> 8)
+    }
+
+    /// Unix mode of the file, if it was made on a Unix system.
+    pub fn unix_mode(&self) -> Option<u32>
+    {
+        match self.version_made_by >> 8
+        {
+            3 => Some(self.external_attributes >> 16),
+            _ => None,
+        }
+    }
+
+    /// True if the file is a symbolic link. The contents of the file are the link target.
+    pub fn is_symlink(&self) -> bool
+    {
+        match self.unix_mode()
+        {
+            Some(mode) => mode & 0o170000 == 0o120000,
+            None => false,
+        }
+    }
+
+    /// Minimum version of the specification needed to extract the file, based on the features it uses.
+    ///
+    /// This is 10 for plain files, 20 for directories, Deflate or encryption, and 45 for files that
+    /// use ZIP64, or need it because a size or offset does not fit in 32 bits.
+    pub fn required_version(&self) -> u16
+    {
+        static ZIP64_LIMIT: u64 = 0xFFFFFFFF;
+        if self.zip64 || self.compressed_size >= ZIP64_LIMIT || self.uncompressed_size >= ZIP64_LIMIT || self.header_start >= ZIP64_LIMIT
+        {
+            45
+        }
+        else if self.compression_method == ::compression::Deflated || self.encrypted || self.file_name.as_slice().ends_with("/")
+        {
+            20
+        }
+        else
+        {
+            10
+        }
+    }
+
+    /// True if reading the contents of the file needs a password.
+    ///
+    /// The name, sizes and checksum of an encrypted file are readable without it.
+    pub fn needs_password(&self) -> bool
+    {
+        self.encrypted
+    }
+
+    /// True if the file is metadata added by macOS: `__MACOSX/` and everything below it, and `.DS_Store` files.
+    ///
+    /// The `._` resource fork files that macOS adds are only recognized below `__MACOSX/`, as
+    /// elsewhere such a name may belong to a regular file.
+    pub fn is_macos_metadata(&self) -> bool
+    {
+        let name = self.file_name.as_slice();
+        let base_name = name.trim_right_chars('/').split('/').last().unwrap_or("");
+        name.split('/').any(|component| component == "__MACOSX")
+            || base_name == ".DS_Store"
+    }
+
+    /// True if the internal attributes mark the file as text.
+    pub fn is_text(&self) -> bool
+    {
+        self.internal_attributes & 1 == 1
+    }
+
+    /// Check decompressed contents of the file against its stored checksum.
+    pub fn verify_crc(&self, data: &[u8]) -> bool
+    {
+        ::crc32::update(0, data) == self.crc32
+    }
+
+    /// The range of bytes holding the compressed data of the file.
+    pub fn data_range(&self) -> DataRange
+    {
+        DataRange { offset: self.data_start, len: self.compressed_size }
+    }
+
+    /// Number of bytes taken by the local header of the file: 30 plus the name and extra field.
+    ///
+    /// The local extra field may differ from the central one, so this is derived from where the
+    /// data starts rather than from the parsed fields. Unlike a plain `u64`, this returns None when
+    /// the data start is unknown: headers parsed with `reader_spec::parse_central_header_from_slice`
+    /// have no local header to take it from, and guessing from the central extra field could be
+    /// wrong by any amount.
+    pub fn header_overhead(&self) -> Option<u64>
+    {
+        static LOCAL_HEADER_SIZE: u64 = 30;
+        if self.data_start < self.header_start + LOCAL_HEADER_SIZE { return None }
+        Some(self.data_start - self.header_start)
+    }
+
+    /// Last modified time as the number of seconds since the Unix epoch.
+    ///
+    /// MS-DOS timestamps carry no timezone. Like most zip tools, this interprets the stored time
+    /// as local time of the machine running this code, so the result depends on its timezone.
+    pub fn modified_unix_time(&self) -> i64
+    {
+        ::util::local_tm_to_unix_time(self.last_modified_time)
+    }
+
+    /// Last modified time as the number of seconds since the Unix epoch, taking the stored time as UTC.
+    ///
+    /// This matches files written by a ZipWriter with `set_use_utc(true)`.
+    pub fn modified_unix_time_utc(&self) -> i64
+    {
+        let mut tm = self.last_modified_time;
+        tm.tm_gmtoff = 0;
+        tm.to_timespec().sec
+    }
+}
+
+#[cfg(test)]
+mod test
+{
+    use time;
+    use compression;
+    use testutil;
+
+    #[test]
+    fn modified_unix_time_is_local()
+    {
+        let mut file = testutil::single_file("a.txt", compression::Stored, b"a");
+        file.last_modified_time = time::strptime("2014-10-15 12:30:00", "%Y-%m-%d %H:%M:%S").unwrap();
+
+        let local = time::at(time::Timespec::new(file.modified_unix_time(), 0));
+        assert_eq!(local.tm_year, 114);
+        assert_eq!(local.tm_mon, 9);
+        assert_eq!(local.tm_mday, 15);
+        assert_eq!(local.tm_hour, 12);
+        assert_eq!(local.tm_min, 30);
+        assert_eq!(local.tm_sec, 0);
+    }
+
+    #[test]
+    fn clone_keeps_name()
+    {
+        let file = testutil::single_file("a.txt", compression::Stored, b"a");
+        let copy = file.clone();
+        assert_eq!(copy.file_name, file.file_name);
+    }
+
+    #[test]
+    fn data_range_locates_data()
+    {
+        let data = testutil::stored_zip(&[("a.txt", b"first"), ("b.txt", b"second")]);
+        let zip = testutil::open_zip(data.clone());
+
+        let file = zip.by_name("b.txt").unwrap();
+        let range = file.data_range();
+        assert_eq!(range.len, 6);
+        assert_eq!(range.offset, file.data_start);
+        assert_eq!(data.slice(range.offset as uint, (range.offset + range.len) as uint), b"second");
+    }
+
+    #[test]
+    fn show_contains_name_and_sizes()
+    {
+        let file = testutil::single_file("shown.txt", compression::Stored, b"12345");
+        let shown = format!("{}", file);
+        assert!(shown.as_slice().contains("shown.txt"));
+        assert!(shown.as_slice().contains("5 -> 5 bytes"));
+    }
+
+    #[test]
+    fn verify_crc_compares_data()
+    {
+        let file = testutil::single_file("a.txt", compression::Stored, b"Hello, World!");
+        assert!(file.verify_crc(b"Hello, World!"));
+        assert!(!file.verify_crc(b"Hello, World?"));
+    }
+
+    #[test]
+    fn text_flag_is_bit_zero_of_internal_attributes()
+    {
+        let zip = testutil::open_zip(testutil::write_zip(|zip|
+        {
+            try!(zip.start_file("text.txt", compression::Stored));
+            try!(zip.set_internal_attributes(1));
+            try!(zip.write(b"plain text"));
+            try!(zip.start_file("binary.bin", compression::Stored));
+            try!(zip.set_internal_attributes(2));
+            zip.write(b"\x00\x01")
+        }));
+        assert!(zip.by_name("text.txt").unwrap().is_text());
+        assert!(!zip.by_name("binary.bin").unwrap().is_text());
+    }
+
+    #[test]
+    fn header_overhead_covers_local_header()
+    {
+        let mut options = ::writer::FileOptions::new(compression::Stored);
+        options.last_access_time = Some(time::at(time::Timespec::new(1400000000, 0)));
+        let zip = testutil::open_zip(testutil::write_zip(|zip|
+        {
+            try!(zip.start_file("a.txt", compression::Stored));
+            try!(zip.write(b"a"));
+            try!(zip.start_file_with_options("dir/b.txt", options.clone()));
+            zip.write(b"b")
+        }));
+
+        assert_eq!(zip.by_name("a.txt").unwrap().header_overhead(), Some(30 + 5));
+        // Local extended timestamp with the modification and access times
+        assert_eq!(zip.by_name("dir/b.txt").unwrap().header_overhead(), Some(30 + 9 + 4 + 9));
+
+        // The second header in the central directory, of which the data start is unknown
+        let directory = zip.central_directory_bytes().unwrap();
+        let (_, consumed) = ::reader_spec::parse_central_header_from_slice(directory.as_slice()).unwrap();
+        let (file, _) = ::reader_spec::parse_central_header_from_slice(directory.slice_from(consumed)).unwrap();
+        assert!(file.header_start > 0);
+        assert_eq!(file.header_overhead(), None);
+    }
+
+    #[test]
+    fn encrypted_file_is_listed_and_needs_password()
+    {
+        let mut data = testutil::stored_zip(&[("secret.txt", b"secret"), ("plain.txt", b"plain")]);
+        let central = testutil::positions(data.as_slice(), b"PK\x01\x02")[0];
+        testutil::put_le_u16(data.as_mut_slice(), central + 8, 1);
+        let zip = testutil::open_zip(data);
+
+        let secret = zip.by_name("secret.txt").unwrap();
+        assert!(secret.needs_password());
+        assert_eq!(secret.uncompressed_size, 6);
+        assert_eq!(secret.crc32, ::crc32::update(0, b"secret"));
+        assert!(!zip.by_name("plain.txt").unwrap().needs_password());
+        assert!(zip.info().encrypted);
+    }
+
+    #[test]
+    fn macos_metadata_is_recognized()
+    {
+        let names = ["__MACOSX/", "__MACOSX/dir/._a.txt", "dir/.DS_Store", ".DS_Store",
+                     "._a.txt", "dir/._b.txt", "dir/a.txt", "DS_Store", "my__MACOSX/a.txt"];
+        let zip = testutil::open_zip(testutil::write_zip(|zip|
+        {
+            for name in names.iter()
+            {
+                try!(zip.start_file(*name, compression::Stored));
+            }
+            Ok(())
+        }));
+
+        let metadata: Vec<&str> = zip.files().filter(|file| file.is_macos_metadata())
+            .map(|file| file.file_name.as_slice()).collect();
+        assert_eq!(metadata, vec!["__MACOSX/", "__MACOSX/dir/._a.txt", "dir/.DS_Store", ".DS_Store"]);
+        let kept: Vec<&str> = zip.files_excluding_macos().map(|file| file.file_name.as_slice()).collect();
+        assert_eq!(kept, vec!["._a.txt", "dir/._b.txt", "dir/a.txt", "DS_Store", "my__MACOSX/a.txt"]);
+    }
 }
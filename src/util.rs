@@ -1,6 +1,20 @@
 use time;
 use time::Tm;
-use std::cell::RefMut;
+use std::cell::{Cell, RefMut};
+use std::rc::Rc;
+use std::sync::Arc;
+
+/// Insert ` (n)` before the extension of the last component of `name`, as in `dir/file (1).txt`.
+pub fn numbered_name(name: &str, n: uint) -> String
+{
+    let base_start = name.rfind('/').map(|i| i + 1).unwrap_or(0);
+    let (stem, extension) = match name.rfind('.')
+    {
+        Some(i) if i > base_start => (name.slice_to(i), name.slice_from(i)),
+        _ => (name, ""),
+    };
+    format!("{} ({}){}", stem, n, extension)
+}
 
 pub fn msdos_datetime_to_tm(time: u16, date: u16) -> Tm
 {
@@ -11,6 +25,10 @@ pub fn msdos_datetime_to_tm(time: u16, date: u16) -> Tm
     let months =  (date & 0b0000000111100000) >> 5;
     let years =   (date & 0b1111111000000000) >> 9;
 
+    // Some archivers write a zero date for "no date", which has day and month 0: use 1980-01-01
+    let days = ::std::cmp::max(days, 1);
+    let months = ::std::cmp::max(months, 1);
+
     let datetime = format!("{:04u}-{:02u}-{:02u} {:02u}:{:02u}:{:02u}",
                            years as uint + 1980,
                            months,
@@ -27,14 +45,328 @@ pub fn msdos_datetime_to_tm(time: u16, date: u16) -> Tm
     }
 }
 
-pub fn tm_to_msdos_time(time: Tm) -> u16
+/// Converts a Tm to MS-DOS (time, date) fields.
+///
+/// MS-DOS dates can only represent the years 1980 to 2107. Earlier times are clamped to
+/// 1980-01-01 00:00:00, later times to 2107-12-31 23:59:58.
+pub fn tm_to_msdos_datetime(time: Tm) -> (u16, u16)
+{
+    if time.tm_year < 80
+    {
+        return (0, (1 << 5) | 1)
+    }
+    if time.tm_year > 207
+    {
+        return ((29 | (59 << 5) | (23 << 11)) as u16, (31 | (12 << 5) | (127 << 9)) as u16)
+    }
+
+    let msdos_time = (time.tm_sec >> 1) | (time.tm_min << 5) | (time.tm_hour << 11);
+    let msdos_date = time.tm_mday | ((time.tm_mon + 1) << 5) | ((time.tm_year - 80) << 9);
+    (msdos_time as u16, msdos_date as u16)
+}
+
+/// Seconds since the Unix epoch of `tm`, taking its fields as local time of this machine.
+///
+/// The local offset is looked up at the instant the fields denote in UTC, and again at the
+/// corrected instant, so times on the other side of a daylight saving change get their own offset.
+pub fn local_tm_to_unix_time(tm: Tm) -> i64
+{
+    let mut utc = tm;
+    utc.tm_gmtoff = 0;
+    let as_utc = utc.to_timespec().sec;
+    let first_offset = time::at(time::Timespec::new(as_utc, 0)).tm_gmtoff as i64;
+    let offset = time::at(time::Timespec::new(as_utc - first_offset, 0)).tm_gmtoff as i64;
+    as_utc - offset
+}
+
+/// Reader that fails once the inner reader produces more than a given number of bytes.
+pub struct SizeLimitReader<R>
+{
+    inner: R,
+    remaining: u64,
+}
+
+impl<R: Reader> SizeLimitReader<R>
+{
+    pub fn new(inner: R, limit: u64) -> SizeLimitReader<R>
+    {
+        SizeLimitReader { inner: inner, remaining: limit, }
+    }
+}
+
+impl<R: Reader> Reader for SizeLimitReader<R>
+{
+    fn read(&mut self, buf: &mut [u8]) -> ::std::io::IoResult<uint>
+    {
+        let count = try!(self.inner.read(buf));
+        if count as u64 > self.remaining
+        {
+            return Err(::std::io::IoError
+                       {
+                           kind: ::std::io::OtherIoError,
+                           desc: "Maximum uncompressed size exceeded",
+                           detail: None,
+                       })
+        }
+        self.remaining -= count as u64;
+        Ok(count)
+    }
+}
+
+/// Writer that adds the number of bytes written to a shared counter.
+pub struct CountingWriter<W>
+{
+    inner: W,
+    count: Rc<Cell<u64>>,
+    capture: Option<Vec<u8>>,
+    observer: Option<Box<FnMut(&[u8]) + 'static>>,
+    // End of the output passed to the observer so far
+    observed_end: u64,
+    // Output after observed_end, which may still be overwritten
+    pending: Vec<u8>,
+}
+
+impl<W: Writer+Seek> CountingWriter<W>
+{
+    pub fn new(inner: W, count: Rc<Cell<u64>>) -> CountingWriter<W>
+    {
+        CountingWriter { inner: inner, count: count, capture: None, observer: None, observed_end: 0, pending: Vec::new(), }
+    }
+
+    /// Pass the output to `observer` in order, each byte once.
+    ///
+    /// Written bytes are held back until `commit_observed` declares them final, so that overwriting
+    /// them is not seen by the observer.
+    pub fn set_observer(&mut self, observer: Box<FnMut(&[u8]) + 'static>)
+    {
+        self.observer = Some(observer);
+    }
+
+    /// Pass the held back output up to `end` to the observer.
+    pub fn commit_observed(&mut self, end: u64)
+    {
+        let observer = match self.observer
+        {
+            Some(ref mut observer) => observer,
+            None => return,
+        };
+        if end <= self.observed_end { return }
+        // Seeking past the end leaves a gap that reads as zeros
+        let count = (end - self.observed_end) as uint;
+        if self.pending.len() < count
+        {
+            let missing = count - self.pending.len();
+            self.pending.grow(missing, 0u8);
+        }
+        (*observer)(self.pending.slice_to(count));
+        self.pending = self.pending.slice_from(count).to_vec();
+        self.observed_end = end;
+    }
+
+    /// Pass all held back output to the observer.
+    pub fn commit_all_observed(&mut self)
+    {
+        let end = self.observed_end + self.pending.len() as u64;
+        self.commit_observed(end);
+    }
+
+    fn observe(&mut self, position: u64, buf: &[u8]) -> ::std::io::IoResult<()>
+    {
+        if position < self.observed_end
+        {
+            return Err(::std::io::IoError
+                       {
+                           kind: ::std::io::OtherIoError,
+                           desc: "Output that was passed to the archive observer was overwritten",
+                           detail: None,
+                       })
+        }
+        let (start, end) = ((position - self.observed_end) as uint, (position - self.observed_end) as uint + buf.len());
+        if self.pending.len() < end
+        {
+            let missing = end - self.pending.len();
+            self.pending.grow(missing, 0u8);
+        }
+        ::std::slice::bytes::copy_memory(self.pending.slice_mut(start, end), buf);
+        Ok(())
+    }
+
+    /// Keep a copy of everything written from now on, until `take_capture` is called.
+    pub fn start_capture(&mut self)
+    {
+        self.capture = Some(Vec::new());
+    }
+
+    pub fn take_capture(&mut self) -> Option<Vec<u8>>
+    {
+        self.capture.take()
+    }
+
+    pub fn into_inner(self) -> W
+    {
+        self.inner
+    }
+}
+
+impl<W: Writer+Seek> Writer for CountingWriter<W>
+{
+    fn write(&mut self, buf: &[u8]) -> ::std::io::IoResult<()>
+    {
+        let position = match self.observer
+        {
+            Some(_) => Some(try!(self.inner.tell())),
+            None => None,
+        };
+        try!(self.inner.write(buf));
+        match position
+        {
+            Some(position) => try!(self.observe(position, buf)),
+            None => {},
+        }
+        self.count.set(self.count.get() + buf.len() as u64);
+        match self.capture
+        {
+            Some(ref mut data) => data.push_all(buf),
+            None => {},
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> ::std::io::IoResult<()>
+    {
+        self.inner.flush()
+    }
+}
+
+impl<W: Seek> Seek for CountingWriter<W>
 {
-    ((time.tm_sec >> 1) | (time.tm_min << 5) | (time.tm_hour << 11)) as u16
+    fn tell(&self) -> ::std::io::IoResult<u64>
+    {
+        self.inner.tell()
+    }
+
+    fn seek(&mut self, pos: i64, style: ::std::io::SeekStyle) -> ::std::io::IoResult<()>
+    {
+        self.inner.seek(pos, style)
+    }
 }
 
-pub fn tm_to_msdos_date(time: Tm) -> u16
+/// Writer that discards all data, keeping track of the size of the output.
+///
+/// Writing a ZIP file to it runs the compression without producing output, to learn the sizes the
+/// real ZIP file would have.
+pub struct DryRunSink
 {
-    (time.tm_mday | ((time.tm_mon + 1) << 5) | ((time.tm_year - 80) << 9)) as u16
+    position: u64,
+    len: u64,
+}
+
+impl DryRunSink
+{
+    /// Create an empty sink.
+    pub fn new() -> DryRunSink
+    {
+        DryRunSink { position: 0, len: 0, }
+    }
+
+    /// Number of bytes the output would have.
+    pub fn len(&self) -> u64
+    {
+        self.len
+    }
+}
+
+impl Writer for DryRunSink
+{
+    fn write(&mut self, buf: &[u8]) -> ::std::io::IoResult<()>
+    {
+        self.position += buf.len() as u64;
+        self.len = ::std::cmp::max(self.len, self.position);
+        Ok(())
+    }
+}
+
+impl Seek for DryRunSink
+{
+    fn tell(&self) -> ::std::io::IoResult<u64>
+    {
+        Ok(self.position)
+    }
+
+    fn seek(&mut self, pos: i64, style: ::std::io::SeekStyle) -> ::std::io::IoResult<()>
+    {
+        let base = match style
+        {
+            ::std::io::SeekSet => 0,
+            ::std::io::SeekCur => self.position as i64,
+            ::std::io::SeekEnd => self.len as i64,
+        };
+        if base + pos < 0
+        {
+            return Err(::std::io::IoError { kind: ::std::io::InvalidInput, desc: "Seek to a negative position", detail: None })
+        }
+        self.position = (base + pos) as u64;
+        Ok(())
+    }
+}
+
+/// Reader over bytes in memory that can be cloned without copying them.
+///
+/// Clones share the bytes but have their own position, and can be sent to other threads. This makes
+/// it a cheap source for `ZipReader::read_file_independent`.
+#[deriving(Clone)]
+pub struct SharedMemReader
+{
+    data: Arc<Vec<u8>>,
+    position: uint,
+}
+
+impl SharedMemReader
+{
+    /// Create a reader over `data`, positioned at its start.
+    pub fn new(data: Vec<u8>) -> SharedMemReader
+    {
+        SharedMemReader { data: Arc::new(data), position: 0, }
+    }
+}
+
+impl Reader for SharedMemReader
+{
+    fn read(&mut self, buf: &mut [u8]) -> ::std::io::IoResult<uint>
+    {
+        if self.position >= self.data.len()
+        {
+            return Err(::std::io::standard_error(::std::io::EndOfFile))
+        }
+        let count = ::std::cmp::min(buf.len(), self.data.len() - self.position);
+        ::std::slice::bytes::copy_memory(buf, self.data.slice(self.position, self.position + count));
+        self.position += count;
+        Ok(count)
+    }
+}
+
+impl Seek for SharedMemReader
+{
+    fn tell(&self) -> ::std::io::IoResult<u64>
+    {
+        Ok(self.position as u64)
+    }
+
+    fn seek(&mut self, pos: i64, style: ::std::io::SeekStyle) -> ::std::io::IoResult<()>
+    {
+        let base = match style
+        {
+            ::std::io::SeekSet => 0,
+            ::std::io::SeekCur => self.position as i64,
+            ::std::io::SeekEnd => self.data.len() as i64,
+        };
+        if base + pos < 0
+        {
+            return Err(::std::io::IoError { kind: ::std::io::InvalidInput, desc: "Seek to a negative position", detail: None })
+        }
+        self.position = (base + pos) as uint;
+        Ok(())
+    }
 }
 
 pub struct RefMutReader<'a, R:'a>
@@ -57,3 +389,46 @@ impl<'a, R: Reader> Reader for RefMutReader<'a, R>
         self.inner.read(buf)
     }
 }
+
+#[cfg(test)]
+mod test
+{
+    use time;
+
+    #[test]
+    fn msdos_datetime_clamps_early_dates()
+    {
+        let tm = time::strptime("1970-06-15 10:00:00", "%Y-%m-%d %H:%M:%S").unwrap();
+        // 1980-01-01 00:00:00
+        assert_eq!(super::tm_to_msdos_datetime(tm), (0, (1 << 5) | 1));
+    }
+
+    #[test]
+    fn msdos_datetime_clamps_late_dates()
+    {
+        let tm = time::strptime("2200-06-15 10:00:00", "%Y-%m-%d %H:%M:%S").unwrap();
+        // 2107-12-31 23:59:58
+        assert_eq!(super::tm_to_msdos_datetime(tm), ((29 | (59 << 5) | (23 << 11)) as u16, (31 | (12 << 5) | (127 << 9)) as u16));
+    }
+
+    #[test]
+    fn zero_msdos_date_is_start_of_1980()
+    {
+        let tm = super::msdos_datetime_to_tm(0, 0);
+        assert_eq!((tm.tm_year, tm.tm_mon, tm.tm_mday), (80, 0, 1));
+        assert_eq!((tm.tm_hour, tm.tm_min, tm.tm_sec), (0, 0, 0));
+        assert_eq!(super::tm_to_msdos_datetime(tm), (0, (1 << 5) | 1));
+    }
+
+    #[test]
+    fn local_time_converts_back_in_summer_and_winter()
+    {
+        for text in ["2014-01-15 08:45:10", "2014-07-15 08:45:10"].iter()
+        {
+            let tm = time::strptime(*text, "%Y-%m-%d %H:%M:%S").unwrap();
+            let local = time::at(time::Timespec::new(super::local_tm_to_unix_time(tm), 0));
+            assert_eq!((local.tm_year, local.tm_mon, local.tm_mday), (tm.tm_year, tm.tm_mon, tm.tm_mday));
+            assert_eq!((local.tm_hour, local.tm_min, local.tm_sec), (8, 45, 10));
+        }
+    }
+}
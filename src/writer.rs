@@ -1,15 +1,21 @@
 use compression;
-use types::ZipFile;
+use types::{ZipFile, HostSystem, HostUnix};
+use reader::ZipReader;
 use spec;
 use writer_spec;
 use crc32;
+use util::CountingWriter;
+use std::cell::Cell;
+use std::collections::HashMap;
 use std::default::Default;
 use std::io;
 use std::io::{IoResult, IoError};
+use std::io::fs;
 use std::mem;
+use std::rc::Rc;
 use time;
 use flate2;
-use flate2::FlateWriter;
+use flate2::{FlateReader, FlateWriter};
 use flate2::writer::DeflateEncoder;
 
 enum GenericZipWriter<W>
@@ -19,6 +25,55 @@ enum GenericZipWriter<W>
     Deflater(DeflateEncoder<W>),
 }
 
+/// What to do with a file of which the name is already present when merging ZIP files.
+#[deriving(Clone, PartialEq, Show)]
+pub enum ConflictPolicy
+{
+    /// Keep the file that was added first
+    SkipDuplicate,
+    /// Keep the file that was added last. The data of the replaced file stays in the output.
+    OverwriteDuplicate,
+    /// Keep both, adding a number to the name of the file that was added last
+    RenameDuplicate,
+}
+
+/// What `add_directory_tree_with` does with entries that are neither files, directories nor symlinks.
+#[deriving(Clone, PartialEq, Show)]
+pub enum SpecialFilePolicy
+{
+    /// Leave them out, with a warning
+    SkipSpecialFiles,
+    /// Fail on the first one
+    ErrorOnSpecialFiles,
+}
+
+/// When to use the ZIP64 extensions, which lift the 4 GiB and 65535 file limits.
+#[deriving(Clone, PartialEq, Show)]
+pub enum Zip64Mode
+{
+    /// Use ZIP64 where a value does not fit. A file that turns out larger than 4 GiB only gets
+    /// ZIP64 sizes in the central directory, as its local header is written before its size is known.
+    Zip64Auto,
+    /// Use ZIP64 for every file and for the end of the central directory
+    Zip64Always,
+    /// Never use ZIP64, and fail when a value does not fit
+    Zip64Never,
+}
+
+/// Merge several ZIP files into one, copying all files without recompressing them.
+///
+/// The sources are copied in order. Returns the destination after the result has been finished.
+pub fn merge_archives<R: Reader+Seek, W: Writer+Seek>(sources: Vec<R>, dest: W, on_conflict: ConflictPolicy) -> IoResult<W>
+{
+    let mut writer = ZipWriter::new(dest);
+    for source in sources.into_iter()
+    {
+        let reader = try!(ZipReader::new(source));
+        try!(writer.merge_from(&reader, on_conflict));
+    }
+    writer.finish()
+}
+
 /// Generator for ZIP files.
 ///
 /// ```
@@ -42,16 +97,103 @@ enum GenericZipWriter<W>
 /// ```
 pub struct ZipWriter<W>
 {
-    inner: GenericZipWriter<W>,
+    inner: GenericZipWriter<CountingWriter<W>>,
+    bytes_emitted: Rc<Cell<u64>>,
     files: Vec<ZipFile>,
+    // Index into files of the last file with each name
+    names: HashMap<String, uint>,
     stats: ZipWriterStats,
+    stored_below: u64,
+    buffered_data: Option<Vec<u8>>,
+    compression_level: flate2::CompressionLevel,
+    writing_to_file: bool,
+    compute_crc: bool,
+    fixed_time: Option<time::Tm>,
+    comment: Vec<u8>,
+    incremental_eocd: bool,
+    // Furthest end of a provisional central directory or of discarded data, which the final one must reach
+    provisional_end: u64,
+    uncompressed_observer: Option<Box<FnMut(&[u8]) + 'static>>,
+    version_made_by: Option<u16>,
+    version_needed: Option<u16>,
+    use_utc: bool,
+    pending_extra_field: Vec<u8>,
+    verify_on_write: bool,
+    use_data_descriptor: bool,
+    data_descriptor_signature: bool,
+    // Applied to the captured data before verifying it, so tests can simulate a faulty compressor
+    capture_fault: Option<fn(&mut [u8])>,
+    zip64_mode: Zip64Mode,
+    alignment: u16,
+    host_system: Option<HostSystem>,
+    write_buffer: Vec<u8>,
+    write_buffer_size: uint,
+    preserve_absolute_paths: bool,
+    check_duplicate_names: bool,
+    // Set once the central directory has been written, after which no files can be added
+    finalized: bool,
+}
+
+/// Options for a file added to a ZIP.
+#[deriving(Clone)]
+pub struct FileOptions
+{
+    /// Compression method used to store the file
+    pub compression_method: compression::CompressionMethod,
+    /// Unix mode of the file, including the file type bits. Recorded with Unix as host system.
+    pub unix_mode: Option<u32>,
+    /// Last access time, recorded in an extended timestamp extra field
+    pub last_access_time: Option<time::Tm>,
+    /// Creation time, recorded in an extended timestamp extra field
+    pub creation_time: Option<time::Tm>,
+    /// Compression level, used when the file is Deflated
+    pub compression_level: flate2::CompressionLevel,
+    /// Last modified time, instead of the current time or the time given to `set_reproducible`
+    pub last_modified_time: Option<time::Tm>,
+}
+
+impl FileOptions
+{
+    /// Options for a file with the given compression method and no further attributes.
+    pub fn new(compression: compression::CompressionMethod) -> FileOptions
+    {
+        FileOptions
+        {
+            compression_method: compression,
+            unix_mode: None,
+            last_access_time: None,
+            creation_time: None,
+            compression_level: flate2::Default,
+            last_modified_time: None,
+        }
+    }
+
+    fn apply(&self, file: &mut ZipFile)
+    {
+        match self.unix_mode
+        {
+            Some(mode) =>
+            {
+                file.version_made_by = (3 << 8) | 20;
+                file.external_attributes = mode << 16;
+            },
+            None => {},
+        }
+        match self.last_modified_time
+        {
+            Some(tm) => file.last_modified_time = tm,
+            None => {},
+        }
+        file.last_access_time = self.last_access_time;
+        file.creation_time = self.creation_time;
+    }
 }
 
 #[deriving(Default)]
 struct ZipWriterStats
 {
     crc32: u32,
-    start: u64,
+    emitted_at_start: u64,
     bytes_written: u64,
 }
 
@@ -60,26 +202,215 @@ fn writer_closed_error<T>() -> IoResult<T>
     Err(IoError { kind: io::Closed, desc: "This writer has been closed", detail: None })
 }
 
+fn check_comment_length(comment: &str) -> IoResult<()>
+{
+    if comment.len() > ::std::u16::MAX as uint
+    {
+        return Err(IoError { kind: io::InvalidInput, desc: "Comment is longer than 65535 bytes", detail: None })
+    }
+    Ok(())
+}
+
+fn new_zip_file(name: &str, compression: compression::CompressionMethod, last_modified_time: time::Tm, header_start: u64) -> ZipFile
+{
+    let mut file = ZipFile
+    {
+        encrypted: false,
+        compression_method: compression,
+        last_modified_time: last_modified_time,
+        crc32: 0,
+        compressed_size: 0,
+        uncompressed_size: 0,
+        file_name: String::from_str(name),
+        file_comment: String::new(),
+        header_start: header_start,
+        data_start: 0,
+        internal_attributes: 0,
+        unsupported_feature: None,
+        version_made_by: 0x14FF,
+        version_needed: 20,
+        external_attributes: 0,
+        disk_number_start: 0,
+        zip64: false,
+        flags: 0,
+        using_data_descriptor: false,
+        last_access_time: None,
+        creation_time: None,
+        extra_field: Vec::new(),
+    };
+    file.version_needed = file.required_version();
+    file
+}
+
+/// The name of a file in the ZIP file for `path`, with its components separated by `/`.
+fn path_to_name(path: &Path, preserve_absolute: bool) -> IoResult<String>
+{
+    let components: Vec<String> = path.components()
+        .map(|component| String::from_utf8_lossy(component).into_string())
+        .collect();
+    if components.iter().any(|component| component.as_slice() == "..")
+    {
+        return Err(IoError { kind: io::InvalidInput, desc: "Path contains a \"..\" component", detail: Some(path.display().to_string()) })
+    }
+    if components.is_empty()
+    {
+        return Err(IoError { kind: io::InvalidInput, desc: "Path has no components", detail: Some(path.display().to_string()) })
+    }
+
+    let mut name = String::new();
+    if preserve_absolute && path.is_absolute() { name.push('/'); }
+    name.push_str(components.connect("/").as_slice());
+    Ok(name)
+}
+
+/// The blocks of an extra field as (header id, data), up to the first incomplete one.
+fn extra_field_blocks<'a>(data: &'a [u8]) -> Vec<(u16, &'a [u8])>
+{
+    let mut blocks = Vec::new();
+    let mut position = 0;
+    while position + 4 <= data.len()
+    {
+        let id = data[position] as u16 | (data[position + 1] as u16 << 8);
+        let len = data[position + 2] as uint | (data[position + 3] as uint << 8);
+        if position + 4 + len > data.len() { break }
+        blocks.push((id, data.slice(position + 4, position + 4 + len)));
+        position += 4 + len;
+    }
+    blocks
+}
+
+fn too_large_error<T>() -> IoResult<T>
+{
+    Err(IoError { kind: io::InvalidInput, desc: "Value does not fit in a ZIP file without ZIP64", detail: None })
+}
+
+/// Decide whether the local header of the file gets a ZIP64 extra field.
+fn use_zip64(mode: Zip64Mode, file: &ZipFile) -> IoResult<bool>
+{
+    match mode
+    {
+        Zip64Always => Ok(true),
+        Zip64Auto => Ok(writer_spec::needs_zip64(file)),
+        Zip64Never if writer_spec::needs_zip64(file) => too_large_error(),
+        Zip64Never => Ok(false),
+    }
+}
+
+fn set_host_system(file: &mut ZipFile, host: Option<HostSystem>)
+{
+    match host
+    {
+        Some(host) => file.version_made_by = ((host as u16) << 8) | 20,
+        None => {},
+    }
+}
+
+fn override_versions(file: &mut ZipFile, version_made_by: Option<u16>, version_needed: Option<u16>)
+{
+    match version_made_by
+    {
+        Some(version) => file.version_made_by = version,
+        None => {},
+    }
+    match version_needed
+    {
+        Some(version) => file.version_needed = version,
+        None => {},
+    }
+}
+
+/// Decompress the data of a file and check it against the recorded size and checksum.
+fn verify_deflated(data: &[u8], file: &ZipFile, check_crc: bool) -> IoResult<()>
+{
+    let mut reader = io::BufReader::new(data).deflate_decode();
+    let mut buf = [0u8, ..4096];
+    let mut crc = 0;
+    let mut len = 0u64;
+    loop
+    {
+        match reader.read(&mut buf)
+        {
+            Ok(n) => { crc = crc32::update(crc, buf.slice_to(n)); len += n as u64; },
+            Err(ref e) if e.kind == io::EndOfFile => break,
+            Err(e) => return Err(e),
+        }
+    }
+
+    if len != file.uncompressed_size || (check_crc && crc != file.crc32)
+    {
+        return Err(IoError { kind: io::OtherIoError, desc: "Compressed data does not match the written data", detail: Some(file.file_name.clone()) })
+    }
+    Ok(())
+}
+
 impl<W: Writer+Seek> Writer for ZipWriter<W>
 {
     fn write(&mut self, buf: &[u8]) -> IoResult<()>
     {
-        if self.files.len() == 0 { return Err(IoError { kind: io::OtherIoError, desc: "No file has been started", detail: None, }) }
-        self.stats.update(buf);
-        match self.inner
+        if !self.writing_to_file { return Err(IoError { kind: io::OtherIoError, desc: "No file has been started", detail: None, }) }
+        self.stats.update(buf, self.compute_crc);
+        match self.uncompressed_observer
         {
-            Storer(ref mut w) => w.write(buf),
-            Deflater(ref mut w) => w.write(buf),
-            Closed => writer_closed_error(),
+            Some(ref mut observer) => (*observer)(buf),
+            None => {},
+        }
+
+        if self.write_buffer.len() + buf.len() > self.write_buffer_size
+        {
+            try!(self.flush_write_buffer());
+        }
+        if buf.len() >= self.write_buffer_size
+        {
+            return self.write_through(buf)
+        }
+        self.write_buffer.push_all(buf);
+        Ok(())
+    }
+}
+
+impl<W: Writer+Seek> ZipWriter<W>
+{
+    fn flush_write_buffer(&mut self) -> IoResult<()>
+    {
+        if self.write_buffer.is_empty() { return Ok(()) }
+        let data = mem::replace(&mut self.write_buffer, Vec::new());
+        let result = self.write_through(data.as_slice());
+        // Keep the allocation for the next writes
+        self.write_buffer = data;
+        self.write_buffer.clear();
+        result
+    }
+
+    fn write_through(&mut self, buf: &[u8]) -> IoResult<()>
+    {
+        let threshold_reached = match self.buffered_data
+        {
+            Some(ref mut data) =>
+            {
+                data.push_all(buf);
+                data.len() as u64 >= self.stored_below
+            },
+            None => return self.inner.write(buf),
+        };
+
+        if threshold_reached
+        {
+            let data = self.buffered_data.take().unwrap();
+            try!(self.inner.switch_to(compression::Deflated, self.compression_level));
+            try!(self.inner.write(data.as_slice()));
         }
+        Ok(())
     }
 }
 
 impl ZipWriterStats
 {
-    fn update(&mut self, buf: &[u8])
+    fn update(&mut self, buf: &[u8], compute_crc: bool)
     {
-        self.crc32 = crc32::update(self.crc32, buf);
+        if compute_crc
+        {
+            self.crc32 = crc32::update(self.crc32, buf);
+        }
         self.bytes_written += buf.len() as u64;
     }
 }
@@ -91,176 +422,2174 @@ impl<W: Writer+Seek> ZipWriter<W>
     /// Before writing to this object, the start_file command should be called.
     pub fn new(inner: W) -> ZipWriter<W>
     {
+        let bytes_emitted = Rc::new(Cell::new(0));
         ZipWriter
         {
-            inner: Storer(inner),
+            inner: Storer(CountingWriter::new(inner, bytes_emitted.clone())),
+            bytes_emitted: bytes_emitted,
             files: Vec::new(),
+            names: HashMap::new(),
             stats: Default::default(),
+            stored_below: 0,
+            buffered_data: None,
+            compression_level: flate2::Default,
+            writing_to_file: false,
+            compute_crc: true,
+            fixed_time: None,
+            comment: b"zip-rs".to_vec(),
+            incremental_eocd: false,
+            provisional_end: 0,
+            uncompressed_observer: None,
+            version_made_by: None,
+            version_needed: None,
+            use_utc: false,
+            pending_extra_field: Vec::new(),
+            verify_on_write: false,
+            use_data_descriptor: false,
+            data_descriptor_signature: true,
+            capture_fault: None,
+            zip64_mode: Zip64Auto,
+            alignment: 0,
+            host_system: None,
+            write_buffer: Vec::new(),
+            write_buffer_size: 0,
+            preserve_absolute_paths: false,
+            check_duplicate_names: false,
+            finalized: false,
         }
     }
 
-    /// Start a new file for with the requested compression method.
-    pub fn start_file(&mut self, name: &str, compression: compression::CompressionMethod) -> IoResult<()>
+    /// Write the given "version made by" for all following files, instead of a value chosen by this crate.
+    ///
+    /// The upper byte is the host system, the lower byte the version of the specification. This
+    /// takes precedence over the host system implied by `FileOptions::unix_mode`.
+    pub fn set_version_made_by(&mut self, version: u16)
     {
-        try!(self.finish_file());
-
-        {
-            let writer = self.inner.get_plain();
-            let header_start = try!(writer.tell());
-
-            let mut file = ZipFile
-            {
-                encrypted: false,
-                compression_method: compression,
-                last_modified_time: time::now(),
-                crc32: 0,
-                compressed_size: 0,
-                uncompressed_size: 0,
-                file_name: String::from_str(name),
-                file_comment: String::new(),
-                header_start: header_start,
-                data_start: 0,
-            };
-            try!(writer_spec::write_local_file_header(writer, &file));
-
-            let header_end = try!(writer.tell());
-            self.stats.start = header_end;
-            file.data_start = header_end;
+        self.version_made_by = Some(version);
+    }
 
-            self.stats.bytes_written = 0;
-            self.stats.crc32 = 0;
+    /// Record the given host system for all following files.
+    ///
+    /// The external attributes of the files are interpreted according to it, so they should be set
+    /// with `set_external_attributes` in the matching format. Starting a file with a Unix mode
+    /// fails unless the host system is Unix.
+    pub fn set_host_system(&mut self, host: HostSystem)
+    {
+        self.host_system = Some(host);
+    }
 
-            self.files.push(file);
+    /// Set the external file attributes of the last started file, in the format of its host system.
+    ///
+    /// The attributes are only stored in the central directory.
+    pub fn set_external_attributes(&mut self, attrs: u32) -> IoResult<()>
+    {
+        match self.files.last_mut()
+        {
+            Some(file) => { file.external_attributes = attrs; Ok(()) },
+            None => Err(IoError { kind: io::OtherIoError, desc: "No file has been started", detail: None, }),
         }
+    }
 
-        try!(self.inner.switch_to(compression));
-
-        Ok(())
+    /// Write the given "version needed to extract" for all following files.
+    ///
+    /// By default it is derived from the features each file uses, see `ZipFile::required_version`.
+    pub fn set_version_needed(&mut self, version: u16)
+    {
+        self.version_needed = Some(version);
     }
 
-    fn finish_file(&mut self) -> IoResult<()>
+    /// Set a function that is called with all data written to files, before it is compressed.
+    ///
+    /// This can be used to compute a hash of the contents of every file without a second pass.
+    pub fn set_uncompressed_observer(&mut self, observer: Box<FnMut(&[u8]) + 'static>)
     {
-        try!(self.inner.switch_to(compression::Stored));
-        let writer = self.inner.get_plain();
+        self.uncompressed_observer = Some(observer);
+    }
 
-        let file = match self.files.last_mut()
+    /// Set a function that is called with all bytes of the ZIP file, including the headers.
+    ///
+    /// The bytes arrive in order and each byte once, as they end up in the output, so they can be
+    /// hashed or sent on while writing. As the local header of a file is updated once the file is
+    /// finished, the output of each file is held in memory and passed on when the file is finished.
+    /// The central directory is passed on when the ZIP file is finished. Fails if anything has
+    /// been written already.
+    pub fn set_archive_observer(&mut self, observer: Box<FnMut(&[u8]) + 'static>) -> IoResult<()>
+    {
+        if self.inner.is_closed() || self.finalized { return writer_closed_error() }
+        if self.writing_to_file || self.bytes_emitted.get() != 0
         {
-            None => return Ok(()),
-            Some(f) => f,
-        };
-        file.crc32 = self.stats.crc32;
-        file.uncompressed_size = self.stats.bytes_written;
-        file.compressed_size = try!(writer.tell()) - self.stats.start;
-
-        try!(writer_spec::update_local_file_header(writer, file));
-        try!(writer.seek(0, io::SeekEnd));
+            return Err(IoError { kind: io::OtherIoError, desc: "The archive observer must be set before writing", detail: None })
+        }
+        self.inner.get_plain().set_observer(observer);
         Ok(())
     }
 
-    /// Finish the last file and write all other zip-structures
+    /// Collect small writes in a buffer of the given size before compressing them (disabled by default).
     ///
-    /// This will return the writer, but one should normally not append any data to the end of the file.
-    /// Note that the zipfile will also be finished on drop.
-    pub fn finish(mut self) -> IoResult<W>
+    /// This reduces the number of writes to an unbuffered inner writer, such as a File. The buffer
+    /// is flushed when it is full and when a file is finished.
+    pub fn set_write_buffer_size(&mut self, bytes: uint)
     {
-        try!(self.finalize());
-        let inner = mem::replace(&mut self.inner, Closed);
-        Ok(inner.unwrap())
+        self.write_buffer_size = bytes;
     }
 
-    fn finalize(&mut self) -> IoResult<()>
+    /// Write a provisional central directory after every finished file (disabled by default).
+    ///
+    /// The provisional central directory is overwritten by the next file, so when the process is
+    /// interrupted before finishing, the output is still a valid ZIP file containing all files that
+    /// were completed. The inner writer is flushed after each provisional central directory. This
+    /// costs rewriting the whole central directory for every file.
+    pub fn set_incremental_eocd(&mut self, incremental: bool)
     {
-        try!(self.finish_file());
-
-        {
-            let writer = self.inner.get_plain();
+        self.incremental_eocd = incremental;
+    }
 
-            let central_start = try!(writer.tell());
-            for file in self.files.iter()
-            {
-                try!(writer_spec::write_central_directory_header(writer, file));
-            }
-            let central_size = try!(writer.tell()) - central_start;
+    /// Make the output reproducible: the same input gives a byte-identical ZIP file.
+    ///
+    /// All files get `mtime` as their last modified time instead of the current time, and the ZIP
+    /// file comment is cleared. All other fields written by this crate are already deterministic.
+    pub fn set_reproducible(&mut self, mtime: time::Tm)
+    {
+        self.fixed_time = Some(mtime);
+        self.comment = Vec::new();
+    }
 
-            let footer = spec::CentralDirectoryEnd
-            {
-                disk_number: 0,
-                disk_with_central_directory: 0,
-                number_of_files_on_this_disk: self.files.len() as u16,
-                number_of_files: self.files.len() as u16,
-                central_directory_size: central_size as u32,
-                central_directory_offset: central_start as u32,
-                zip_file_comment: b"zip-rs".to_vec(),
-            };
+    /// Record the current time in UTC instead of local time (disabled by default).
+    ///
+    /// MS-DOS timestamps carry no timezone, and most tools interpret them as local time. Read such
+    /// files back with `ZipFile::modified_unix_time_utc` to avoid drifting by the local offset.
+    pub fn set_use_utc(&mut self, use_utc: bool)
+    {
+        self.use_utc = use_utc;
+    }
 
-            try!(footer.write(writer));
+    /// Add an extra field block to the next file that is started, not to the current one.
+    ///
+    /// The block is written with the given header id in both the local and the central header.
+    /// Multiple blocks can be added, and are written in order. Starting the file fails if the blocks
+    /// together with those written by this crate, such as ZIP64 and alignment, exceed 65535 bytes.
+    pub fn add_extra_field_to_next_file(&mut self, id: u16, data: &[u8]) -> IoResult<()>
+    {
+        if self.pending_extra_field.len() + 4 + data.len() > ::std::u16::MAX as uint
+        {
+            return Err(IoError { kind: io::InvalidInput, desc: "Extra field is longer than 65535 bytes", detail: None })
         }
+        let mut block = io::MemWriter::new();
+        try!(block.write_le_u16(id));
+        try!(block.write_le_u16(data.len() as u16));
+        try!(block.write(data));
+        self.pending_extra_field.push_all(block.get_ref());
+        Ok(())
+    }
 
+    /// Set the comment of the ZIP file.
+    ///
+    /// The comment is stored as UTF-8 without a byte order mark. It may be at most 65535 bytes long.
+    pub fn set_comment(&mut self, comment: &str) -> IoResult<()>
+    {
+        try!(check_comment_length(comment));
+        self.comment = comment.as_bytes().to_vec();
         Ok(())
     }
-}
 
-#[unsafe_destructor]
-impl<W: Writer+Seek> Drop for ZipWriter<W>
-{
-    fn drop(&mut self)
+    /// Set the comment of the last started file.
+    ///
+    /// The comment is stored in the central directory as UTF-8 without a byte order mark. It may be
+    /// at most 65535 bytes long.
+    pub fn set_file_comment(&mut self, comment: &str) -> IoResult<()>
     {
-        if !self.inner.is_closed()
+        try!(check_comment_length(comment));
+        match self.files.last_mut()
         {
-            match self.finalize()
-            {
-                Ok(_) => {},
-                Err(e) => warn!("ZipWriter drop failed: {}", e),
-            }
+            Some(file) => { file.file_comment = String::from_str(comment); Ok(()) },
+            None => Err(IoError { kind: io::OtherIoError, desc: "No file has been started", detail: None, }),
         }
     }
-}
 
-impl<W: Writer+Seek> GenericZipWriter<W>
-{
-    fn switch_to(&mut self, compression: compression::CompressionMethod) -> IoResult<()>
+    fn file_time(&self) -> time::Tm
     {
-        let bare = match mem::replace(self, Closed)
+        match self.fixed_time
         {
-            Storer(w) => w,
-            Deflater(w) => try!(w.finish()),
-            Closed => return writer_closed_error(),
-        };
+            Some(tm) => tm,
+            None if self.use_utc => time::now_utc(),
+            None => time::now(),
+        }
+    }
 
-        *self = match compression
-        {
-            compression::Stored => Storer(bare),
-            compression::Deflated => Deflater(bare.deflate_encode(flate2::Default)),
-            _ => return Err(IoError { kind: io::OtherIoError, desc: "Unsupported compression requested", detail: None }),
-        };
+    /// Decompress every Deflated file after writing it, and fail if it does not match (disabled by default).
+    ///
+    /// The size, and the checksum unless disabled, of the decompressed data are compared to what was
+    /// written. This guards against a faulty compressor, at the cost of keeping the compressed data
+    /// of the current file in memory and decompressing it again. A file that fails is removed, so
+    /// the finished ZIP file only holds the files that passed.
+    pub fn set_verify_on_write(&mut self, verify: bool)
+    {
+        self.verify_on_write = verify;
+    }
 
-        Ok(())
+    /// Write the checksum and sizes of started files in a data descriptor after their data (disabled by default).
+    ///
+    /// The local header is then written once and never updated, so no seeking is needed to finish a
+    /// file. As the compression method must be known when the local header is written,
+    /// `set_stored_below` has no effect. Files written with `write_file_known` or copied raw have
+    /// known sizes and get no data descriptor.
+    pub fn set_use_data_descriptor(&mut self, use_data_descriptor: bool)
+    {
+        self.use_data_descriptor = use_data_descriptor;
     }
 
-    fn is_closed(&self) -> bool
+    /// Write the optional signature 0x08074b50 before each data descriptor (enabled by default).
+    ///
+    /// Most readers accept both forms, but some require the signature and others reject it.
+    pub fn set_data_descriptor_signature(&mut self, signature: bool)
     {
-        match *self
-        {
-            Closed => true,
-            _ => false,
-        }
+        self.data_descriptor_signature = signature;
     }
 
-    fn get_plain(&mut self) -> &mut W
+    /// Align the data of Stored files to a multiple of `bytes`, like zipalign (disabled by default).
+    ///
+    /// The local header is padded with an extra field block. This allows memory-mapping the
+    /// contents of Stored files. Files are aligned when they are started as Stored, so files that
+    /// are only stored because of `set_stored_below` are not aligned.
+    pub fn set_alignment(&mut self, bytes: u16)
     {
-        match *self
-        {
-            Storer(ref mut w) => w,
-            _ => panic!("Should have switched to stored beforehand"),
-        }
+        self.alignment = bytes;
     }
 
-    fn unwrap(self) -> W
+    /// Keep the leading `/` of absolute paths passed to `start_file_from_path` (disabled by default).
+    ///
+    /// Most tools extract such names relative to the destination anyway, but some refuse them or
+    /// write outside of it. Only enable this when the consumer of the ZIP file expects absolute names.
+    pub fn set_preserve_absolute_paths(&mut self, preserve: bool)
     {
-        match self
-        {
-            Storer(w) => w,
-            _ => panic!("Should have switched to stored beforehand"),
-        }
+        self.preserve_absolute_paths = preserve;
+    }
+
+    /// Reject files with a name that was added before (disabled by default).
+    ///
+    /// Adding a file with a name that is already present then fails with `PathAlreadyExists`. A
+    /// directory conflicts with a file of the same name, so `foo/` and `foo` cannot both be added.
+    /// Without this, such files coexist, as the ZIP specification allows.
+    pub fn set_check_duplicate_names(&mut self, check: bool)
+    {
+        self.check_duplicate_names = check;
+    }
+
+    /// Choose when to use the ZIP64 extensions (`Zip64Auto` by default).
+    pub fn set_zip64(&mut self, mode: Zip64Mode)
+    {
+        self.zip64_mode = mode;
+    }
+
+    /// Enable or disable computing the checksum of written files (enabled by default).
+    ///
+    /// When disabled, the checksum of every file is stored as 0. Readers that validate checksums,
+    /// including ZipReader, will reject the contents of such files. Only use this when integrity is
+    /// guaranteed by other means.
+    pub fn set_compute_crc(&mut self, compute_crc: bool)
+    {
+        self.compute_crc = compute_crc;
+    }
+
+    /// Store files smaller than the given number of bytes, even when they are started as Deflated.
+    ///
+    /// Deflating very small files usually makes them larger. To decide, the data of a Deflated file
+    /// is buffered in memory until the threshold is reached. A threshold of 0 disables this.
+    pub fn set_stored_below(&mut self, bytes: u64)
+    {
+        self.stored_below = bytes;
+    }
+
+    /// Start a new file for with the requested compression method.
+    pub fn start_file(&mut self, name: &str, compression: compression::CompressionMethod) -> IoResult<()>
+    {
+        self.start_file_with_options(name, FileOptions::new(compression))
+    }
+
+    /// Start a new file named after a path, with its components separated by `/`.
+    ///
+    /// The leading `/` of an absolute path is stripped, unless `set_preserve_absolute_paths` is
+    /// enabled. Paths with `..` components are rejected, as they would point outside the directory
+    /// the ZIP file is extracted to.
+    pub fn start_file_from_path(&mut self, path: &Path, compression: compression::CompressionMethod) -> IoResult<()>
+    {
+        let name = try!(path_to_name(path, self.preserve_absolute_paths));
+        self.start_file(name.as_slice(), compression)
+    }
+
+    /// Start a new file with the requested options.
+    pub fn start_file_with_options(&mut self, name: &str, options: FileOptions) -> IoResult<()>
+    {
+        if self.inner.is_closed() || self.finalized { return writer_closed_error() }
+        try!(self.check_unused_name(name));
+        match self.host_system
+        {
+            Some(host) if host != HostUnix && options.unix_mode.is_some() =>
+                return Err(IoError { kind: io::InvalidInput, desc: "A Unix mode needs the Unix host system", detail: None }),
+            _ => {},
+        }
+        try!(self.finish_file());
+        let last_modified_time = self.file_time();
+        let compression = options.compression_method;
+
+        {
+            let writer = self.inner.get_plain();
+            let header_start = try!(writer.tell());
+
+            let mut file = new_zip_file(name, compression, last_modified_time, header_start);
+            options.apply(&mut file);
+            file.using_data_descriptor = self.use_data_descriptor;
+            set_host_system(&mut file, self.host_system);
+            override_versions(&mut file, self.version_made_by, self.version_needed);
+            file.extra_field = mem::replace(&mut self.pending_extra_field, Vec::new());
+            file.zip64 = try!(use_zip64(self.zip64_mode, &file));
+            let alignment = if compression == compression::Stored { self.alignment } else { 0 };
+            try!(writer_spec::write_local_file_header(writer, &file, alignment));
+
+            file.data_start = try!(writer.tell());
+            if self.verify_on_write { writer.start_capture(); }
+
+            self.stats.emitted_at_start = self.bytes_emitted.get();
+            self.stats.bytes_written = 0;
+            self.stats.crc32 = 0;
+
+            self.names.insert(file.file_name.clone(), self.files.len());
+            self.files.push(file);
+        }
+        self.writing_to_file = true;
+        self.compression_level = options.compression_level;
+
+        if compression == compression::Deflated && self.stored_below > 0 && !self.use_data_descriptor
+        {
+            self.buffered_data = Some(Vec::new());
+        }
+        else
+        {
+            try!(self.inner.switch_to(compression, options.compression_level));
+        }
+
+        Ok(())
+    }
+
+    /// Add an empty directory.
+    ///
+    /// Directories are identified by a trailing `/`, which is appended when `name` lacks it. As
+    /// `foo/` and `foo` are different names, a directory and a file with the same base name can
+    /// both be added, as the ZIP specification allows, unless `set_check_duplicate_names` is enabled.
+    pub fn add_directory(&mut self, name: &str) -> IoResult<()>
+    {
+        let mut name = String::from_str(name);
+        if !name.as_slice().ends_with("/") { name.push('/'); }
+
+        try!(self.start_file(name.as_slice(), compression::Stored));
+        self.finish_file()
+    }
+
+    /// Add all files and directories below `root`, with their paths relative to `root` as names.
+    ///
+    /// The Unix modes of the files are recorded. Symlinks are added as symlinks, with their target
+    /// as contents, and are never followed. Entries that are neither regular files, directories nor
+    /// symlinks, such as sockets and devices, are skipped.
+    pub fn add_directory_tree(&mut self, root: &Path, compression: compression::CompressionMethod) -> IoResult<()>
+    {
+        self.add_directory_tree_with(root, compression, SkipSpecialFiles)
+    }
+
+    /// Like `add_directory_tree`, choosing what happens to special files such as sockets and devices.
+    pub fn add_directory_tree_with(&mut self, root: &Path, compression: compression::CompressionMethod,
+                                   special_files: SpecialFilePolicy) -> IoResult<()>
+    {
+        try!(self.add_tree_entries(root, root, compression, special_files));
+        self.finish_file()
+    }
+
+    fn add_tree_entries(&mut self, root: &Path, dir: &Path, compression: compression::CompressionMethod,
+                        special_files: SpecialFilePolicy) -> IoResult<()>
+    {
+        let mut paths = try!(fs::readdir(dir));
+        paths.sort_by(|a, b| a.as_vec().cmp(b.as_vec()));
+
+        for path in paths.iter()
+        {
+            // lstat does not follow symlinks, so a link to a parent directory cannot cause a cycle
+            let stat = try!(fs::lstat(path));
+            let relative = path.path_relative_from(root).unwrap();
+            let mut name = relative.components()
+                .map(|component| String::from_utf8_lossy(component).into_string())
+                .collect::<Vec<String>>()
+                .connect("/");
+
+            let file_type = match stat.kind
+            {
+                io::TypeFile => 0o100000,
+                io::TypeDirectory => { name.push('/'); 0o040000 },
+                io::TypeSymlink => 0o120000,
+                _ if special_files == ErrorOnSpecialFiles =>
+                    return Err(IoError { kind: io::InvalidInput, desc: "Cannot add a special file", detail: Some(path.display().to_string()) }),
+                _ => { warn!("Skipping special file {}", path.display()); continue },
+            };
+
+            let mut options = FileOptions::new(if stat.kind == io::TypeFile { compression } else { compression::Stored });
+            options.unix_mode = Some(file_type | stat.perm.bits());
+            try!(self.start_file_with_options(name.as_slice(), options));
+
+            match stat.kind
+            {
+                io::TypeFile =>
+                {
+                    let mut file = try!(io::File::open(path));
+                    try!(io::util::copy(&mut file, self));
+                },
+                io::TypeSymlink =>
+                {
+                    let target = try!(fs::readlink(path));
+                    try!(self.write(target.as_vec()));
+                },
+                _ => try!(self.add_tree_entries(root, path, compression, special_files)),
+            }
+        }
+        Ok(())
+    }
+
+    /// Add a file from the file system under the given name, keeping its Unix mode and last modified time.
+    pub fn add_file(&mut self, path: &Path, name_in_archive: &str, compression: compression::CompressionMethod) -> IoResult<()>
+    {
+        let stat = try!(fs::stat(path));
+        if stat.kind != io::TypeFile
+        {
+            return Err(IoError { kind: io::InvalidInput, desc: "Path is not a regular file", detail: Some(path.display().to_string()) })
+        }
+
+        let modified = time::Timespec::new((stat.modified / 1000) as i64, 0);
+        let mut options = FileOptions::new(compression);
+        options.unix_mode = Some(0o100000 | stat.perm.bits());
+        options.last_modified_time = Some(if self.use_utc { time::at_utc(modified) } else { time::at(modified) });
+        try!(self.start_file_with_options(name_in_archive, options));
+
+        let mut file = try!(io::File::open(path));
+        try!(io::util::copy(&mut file, self));
+        self.finish_file()
+    }
+
+    /// Write a complete Stored file from a slice, such as a memory-mapped file.
+    ///
+    /// The slice is passed to the inner writer in a single call, avoiding intermediate copies.
+    pub fn write_stored_slice(&mut self, name: &str, data: &[u8]) -> IoResult<()>
+    {
+        try!(self.start_file(name, compression::Stored));
+        try!(self.write(data));
+        self.finish_file()
+    }
+
+    /// Write a complete file consisting of `count` copies of `byte`, without allocating all of it.
+    pub fn write_repeated(&mut self, name: &str, compression: compression::CompressionMethod, byte: u8, count: u64) -> IoResult<()>
+    {
+        static CHUNK_SIZE: u64 = 64 * 1024;
+        try!(self.start_file(name, compression));
+
+        let chunk = Vec::from_elem(::std::cmp::min(count, CHUNK_SIZE) as uint, byte);
+        let mut remaining = count;
+        while remaining > 0
+        {
+            let len = ::std::cmp::min(remaining, CHUNK_SIZE);
+            try!(self.write(chunk.slice_to(len as uint)));
+            remaining -= len;
+        }
+        self.finish_file()
+    }
+
+    /// Write a Stored file listing all files written so far, one per line as `name\tsize\tcrc`.
+    ///
+    /// The size is the uncompressed size, the checksum is in hexadecimal. Call this after all other
+    /// files have been added, as later files are not listed.
+    pub fn write_index_entry(&mut self, name: &str) -> IoResult<()>
+    {
+        if self.inner.is_closed() || self.finalized { return writer_closed_error() }
+        try!(self.finish_file());
+
+        let mut index = io::MemWriter::new();
+        for file in self.files.iter()
+        {
+            try!(write!(&mut index, "{}\t{}\t{:08x}\n", file.file_name, file.uncompressed_size, file.crc32));
+        }
+        self.write_stored_slice(name, index.get_ref())
+    }
+
+    /// The information of all files written so far.
+    ///
+    /// The checksum and sizes of a file are final once the next file is started. Use
+    /// `finish_with_files` to also get the final sizes of the last file.
+    pub fn files(&self) -> &[ZipFile]
+    {
+        self.files.as_slice()
+    }
+
+    /// The information of the last file written with the given name.
+    pub fn by_name(&self, name: &str) -> Option<&ZipFile>
+    {
+        match self.names.get(&name.to_string())
+        {
+            Some(&index) => Some(&self.files[index]),
+            None => None,
+        }
+    }
+
+    /// True when a file has been started and data can be written to it.
+    pub fn has_open_file(&self) -> bool
+    {
+        self.writing_to_file
+    }
+
+    /// Ratio between the compressed and uncompressed size of the current file so far.
+    ///
+    /// Returns None when no file is open or nothing has been written to it yet. Data still buffered
+    /// by the compressor is not counted, so the ratio lags behind slightly.
+    pub fn current_entry_ratio(&self) -> Option<f64>
+    {
+        if !self.writing_to_file || self.stats.bytes_written == 0 { return None }
+        let emitted = self.bytes_emitted.get() - self.stats.emitted_at_start;
+        Some(emitted as f64 / self.stats.bytes_written as f64)
+    }
+
+    /// Write a complete file of which the checksum and sizes are known in advance.
+    ///
+    /// The data is written as is, so it must already be compressed with the given compression
+    /// method. Because the local header is written with the final values, it never needs to be
+    /// updated afterwards.
+    pub fn write_file_known(&mut self, name: &str, compression: compression::CompressionMethod,
+                            crc32: u32, uncompressed_size: u64, compressed_size: u64, data: &[u8]) -> IoResult<()>
+    {
+        if self.inner.is_closed() || self.finalized { return writer_closed_error() }
+        try!(self.check_unused_name(name));
+        if data.len() as u64 != compressed_size
+        {
+            return Err(IoError { kind: io::InvalidInput, desc: "Data length does not match the compressed size", detail: None })
+        }
+        try!(self.finish_file());
+        let last_modified_time = self.file_time();
+
+        let writer = self.inner.get_plain();
+        let header_start = try!(writer.tell());
+
+        let mut file = new_zip_file(name, compression, last_modified_time, header_start);
+        file.crc32 = crc32;
+        file.uncompressed_size = uncompressed_size;
+        file.compressed_size = compressed_size;
+        set_host_system(&mut file, self.host_system);
+        override_versions(&mut file, self.version_made_by, self.version_needed);
+        file.extra_field = mem::replace(&mut self.pending_extra_field, Vec::new());
+        file.zip64 = try!(use_zip64(self.zip64_mode, &file));
+        if file.zip64 && self.version_needed.is_none() { file.version_needed = file.required_version(); }
+        let alignment = if compression == compression::Stored { self.alignment } else { 0 };
+        try!(writer_spec::write_local_file_header(writer, &file, alignment));
+
+        file.data_start = try!(writer.tell());
+        try!(writer.write(data));
+        let end = try!(writer.tell());
+        writer.commit_observed(end);
+
+        self.names.insert(file.file_name.clone(), self.files.len());
+        self.files.push(file);
+        self.write_provisional_central_directory()
+    }
+
+    /// Copy all files from another ZIP file, recompressing each with the method chosen by `choose`.
+    ///
+    /// The names, comments, times, attributes and retained extra fields of the files are kept, as
+    /// is their "version made by" unless it is set with `set_version_made_by`. ZIP64 blocks are
+    /// left out of the extra fields, as the writer adds its own with the new sizes. A file that
+    /// cannot be decompressed, or of which the extra field becomes too long, fails before anything
+    /// is written for it.
+    pub fn recompress_from<R: Reader+Seek>(&mut self, source: &ZipReader<R>,
+                                           choose: |&ZipFile| -> compression::CompressionMethod) -> IoResult<()>
+    {
+        for file in source.files()
+        {
+            let mut reader = try!(source.read_file(file));
+
+            let mut options = FileOptions::new(choose(file));
+            options.last_modified_time = Some(file.last_modified_time);
+            options.last_access_time = file.last_access_time;
+            options.creation_time = file.creation_time;
+            for &(id, data) in extra_field_blocks(file.extra_field.as_slice()).iter()
+            {
+                if id == 0x0001 { continue }
+                try!(self.add_extra_field_to_next_file(id, data));
+            }
+            try!(self.start_file_with_options(file.file_name.as_slice(), options));
+            {
+                let new_file = self.files.last_mut().unwrap();
+                new_file.file_comment = file.file_comment.clone();
+                if self.version_made_by.is_none() { new_file.version_made_by = file.version_made_by; }
+                new_file.external_attributes = file.external_attributes;
+                new_file.internal_attributes = file.internal_attributes;
+            }
+            try!(io::util::copy(&mut reader, self));
+        }
+        Ok(())
+    }
+
+    /// Copy a file from another ZIP without decompressing and recompressing it.
+    pub fn raw_copy_file<R: Reader+Seek>(&mut self, source: &ZipReader<R>, file: &ZipFile) -> IoResult<()>
+    {
+        self.raw_copy_file_rename(source, file, file.file_name.as_slice())
+    }
+
+    /// Copy a file from another ZIP under a new name, without decompressing and recompressing it.
+    ///
+    /// All other information of the file, including its checksum and sizes, is kept.
+    pub fn raw_copy_file_rename<R: Reader+Seek>(&mut self, source: &ZipReader<R>, file: &ZipFile, name: &str) -> IoResult<()>
+    {
+        let mut raw_reader = try!(source.read_raw_file(file));
+        self.raw_copy_from(&mut raw_reader, file, name)
+    }
+
+    /// Write a file from its information and raw data, as given by `ZipReader::raw_entries`.
+    pub fn raw_write_file(&mut self, file: &ZipFile, data: &[u8]) -> IoResult<()>
+    {
+        if data.len() as u64 != file.compressed_size
+        {
+            return Err(IoError { kind: io::InvalidInput, desc: "Data length does not match the compressed size", detail: None })
+        }
+        let mut raw_reader = io::BufReader::new(data);
+        self.raw_copy_from(&mut raw_reader, file, file.file_name.as_slice())
+    }
+
+    fn raw_copy_from<R: Reader>(&mut self, raw_reader: &mut R, file: &ZipFile, name: &str) -> IoResult<()>
+    {
+        if self.inner.is_closed() || self.finalized { return writer_closed_error() }
+        try!(self.check_unused_name(name));
+        try!(self.finish_file());
+
+        {
+            let writer = self.inner.get_plain();
+
+            let mut new_file = file.clone();
+            new_file.file_name = String::from_str(name);
+            new_file.header_start = try!(writer.tell());
+            new_file.using_data_descriptor = false;
+            new_file.zip64 = try!(use_zip64(self.zip64_mode, &new_file));
+            if new_file.zip64 { new_file.version_needed = ::std::cmp::max(new_file.version_needed, 45); }
+            let alignment = if new_file.compression_method == compression::Stored { self.alignment } else { 0 };
+            try!(writer_spec::write_local_file_header(writer, &new_file, alignment));
+
+            new_file.data_start = try!(writer.tell());
+            try!(io::util::copy(raw_reader, writer));
+            let end = try!(writer.tell());
+            writer.commit_observed(end);
+
+            self.names.insert(new_file.file_name.clone(), self.files.len());
+            self.files.push(new_file);
+        }
+
+        self.write_provisional_central_directory()
+    }
+
+    /// Copy all files from another ZIP without recompressing them, resolving duplicate names by `on_conflict`.
+    pub fn merge_from<R: Reader+Seek>(&mut self, source: &ZipReader<R>, on_conflict: ConflictPolicy) -> IoResult<()>
+    {
+        for file in source.files()
+        {
+            let existing = self.names.get(&file.file_name).map(|&index| index);
+            match (existing, on_conflict)
+            {
+                (None, _) => try!(self.raw_copy_file(source, file)),
+                (Some(_), SkipDuplicate) => {},
+                (Some(index), OverwriteDuplicate) =>
+                {
+                    try!(self.finish_file());
+                    self.remove_file(index);
+                    try!(self.raw_copy_file(source, file));
+                },
+                (Some(_), RenameDuplicate) =>
+                {
+                    let name = self.unused_name(file.file_name.as_slice());
+                    try!(self.raw_copy_file_rename(source, file, name.as_slice()));
+                },
+            }
+        }
+        Ok(())
+    }
+
+    /// Fail if a file or directory with this name was added before, when duplicate names are checked.
+    fn check_unused_name(&self, name: &str) -> IoResult<()>
+    {
+        if !self.check_duplicate_names { return Ok(()) }
+        let base_name = name.trim_right_chars('/');
+        let directory_name = format!("{}/", base_name);
+        if !self.names.contains_key(&base_name.to_string()) && !self.names.contains_key(&directory_name) { return Ok(()) }
+        Err(IoError { kind: io::PathAlreadyExists, desc: "A file with this name has already been added", detail: Some(name.to_string()) })
+    }
+
+    /// Remove a finished file from the central directory, keeping the name index in sync.
+    fn remove_file(&mut self, index: uint)
+    {
+        let removed = match self.files.remove(index)
+        {
+            Some(file) => file,
+            None => return,
+        };
+        for (_, i) in self.names.iter_mut()
+        {
+            if *i > index { *i -= 1; }
+        }
+        // An earlier file may have the same name
+        match self.files.iter().rposition(|f| f.file_name == removed.file_name)
+        {
+            Some(earlier) => { self.names.insert(removed.file_name, earlier); },
+            None => { self.names.remove(&removed.file_name); },
+        }
+    }
+
+    /// Find a name like `dir/file (1).txt` that is not used yet.
+    fn unused_name(&self, name: &str) -> String
+    {
+        let mut n = 1u;
+        loop
+        {
+            let candidate = ::util::numbered_name(name, n);
+            if !self.names.contains_key(&candidate) { return candidate }
+            n += 1;
+        }
+    }
+
+    /// Set or clear a general purpose flag bit of the last started file.
+    ///
+    /// This is meant for interoperability testing. Bits that change how a file must be read, such
+    /// as bit 5 for compressed patched data, make the result unreadable for most tools. The bits
+    /// for encryption, data descriptors and UTF-8 names are managed by this crate and ignored.
+    /// The local header is only updated while the file is still open.
+    pub fn set_gp_flag(&mut self, bit: u8, value: bool) -> IoResult<()>
+    {
+        if bit >= 16
+        {
+            return Err(IoError { kind: io::InvalidInput, desc: "General purpose flags only have 16 bits", detail: None })
+        }
+        match self.files.last_mut()
+        {
+            Some(file) =>
+            {
+                if value { file.flags |= 1 << bit as uint } else { file.flags &= !(1 << bit as uint) }
+                Ok(())
+            },
+            None => Err(IoError { kind: io::OtherIoError, desc: "No file has been started", detail: None, }),
+        }
+    }
+
+    /// Set the internal file attributes of the last started file.
+    ///
+    /// Bit 0 marks the file as text. The attributes are only stored in the central directory.
+    pub fn set_internal_attributes(&mut self, attrs: u16) -> IoResult<()>
+    {
+        match self.files.last_mut()
+        {
+            Some(file) => { file.internal_attributes = attrs; Ok(()) },
+            None => Err(IoError { kind: io::OtherIoError, desc: "No file has been started", detail: None, }),
+        }
+    }
+
+    fn finish_file(&mut self) -> IoResult<()>
+    {
+        if self.writing_to_file { try!(self.flush_write_buffer()); }
+        try!(self.inner.switch_to(compression::Stored, flate2::Default));
+        if !self.writing_to_file { return Ok(()) }
+        self.writing_to_file = false;
+
+        let (data_end, verify_error) = {
+            let writer = self.inner.get_plain();
+
+            let file = match self.files.last_mut()
+            {
+                None => return Ok(()),
+                Some(f) => f,
+            };
+
+            match self.buffered_data.take()
+            {
+                Some(data) =>
+                {
+                    try!(writer.write(data.as_slice()));
+                    file.compression_method = compression::Stored;
+                },
+                None => {},
+            }
+            let data_end = try!(writer.tell());
+            file.crc32 = self.stats.crc32;
+            file.uncompressed_size = self.stats.bytes_written;
+            // Count the bytes that reached the output instead of trusting the seek position
+            file.compressed_size = self.bytes_emitted.get() - self.stats.emitted_at_start;
+
+            if self.zip64_mode == Zip64Never && writer_spec::needs_zip64(file) { return too_large_error() }
+
+            // Buffering may have changed the compression method
+            if self.version_needed.is_none() { file.version_needed = file.required_version(); }
+
+            let verified = match writer.take_capture()
+            {
+                Some(ref mut data) if file.compression_method == compression::Deflated =>
+                {
+                    match self.capture_fault { Some(fault) => fault(data.as_mut_slice()), None => {} }
+                    verify_deflated(data.as_slice(), file, self.compute_crc)
+                },
+                _ => Ok(()),
+            };
+
+            match verified
+            {
+                Ok(()) if file.using_data_descriptor =>
+                {
+                    try!(writer_spec::write_data_descriptor(writer, file, self.data_descriptor_signature));
+                    let end = try!(writer.tell());
+                    writer.commit_observed(end);
+                    (data_end, None)
+                },
+                Ok(()) =>
+                {
+                    try!(writer_spec::update_local_file_header(writer, file));
+                    // Not SeekEnd: a provisional central directory may be present after the data
+                    try!(writer.seek(data_end as i64, io::SeekSet));
+                    writer.commit_observed(data_end);
+                    (data_end, None)
+                },
+                Err(e) =>
+                {
+                    // The next file or the central directory takes the place of the corrupt one
+                    try!(writer.seek(file.header_start as i64, io::SeekSet));
+                    (data_end, Some(e))
+                },
+            }
+        };
+
+        match verify_error
+        {
+            Some(e) =>
+            {
+                let index = self.files.len() - 1;
+                self.remove_file(index);
+                // Make the final central directory cover the stale data, so it is zeroed
+                self.provisional_end = ::std::cmp::max(self.provisional_end, data_end);
+                Err(e)
+            },
+            None => self.write_provisional_central_directory(),
+        }
+    }
+
+    fn write_provisional_central_directory(&mut self) -> IoResult<()>
+    {
+        if !self.incremental_eocd { return Ok(()) }
+
+        let central_start = try!(self.inner.get_plain().tell());
+        try!(self.write_central_directory());
+        let end = try!(self.inner.get_plain().tell());
+        self.provisional_end = ::std::cmp::max(self.provisional_end, end);
+        try!(self.inner.get_plain().seek(central_start as i64, io::SeekSet));
+        // Push the directory out of any buffers, so it is there when the process dies during the next file
+        self.inner.get_plain().flush()
+    }
+
+    /// Finish the last file and write all other zip-structures
+    ///
+    /// This will return the writer, but one should normally not append any data to the end of the file.
+    /// Note that the zipfile will also be finished on drop.
+    pub fn finish(mut self) -> IoResult<W>
+    {
+        try!(self.finalize());
+        let inner = mem::replace(&mut self.inner, Closed);
+        Ok(inner.unwrap().into_inner())
+    }
+
+    /// Like `finish`, but also returns the information of all files with their final checksums and sizes.
+    ///
+    /// Together with a `DryRunSink` this gives the sizes of the files, and with `DryRunSink::len` the
+    /// size of the whole ZIP file, before writing the real one.
+    pub fn finish_with_files(mut self) -> IoResult<(W, Vec<ZipFile>)>
+    {
+        try!(self.finalize());
+        let inner = mem::replace(&mut self.inner, Closed);
+        let files = mem::replace(&mut self.files, Vec::new());
+        Ok((inner.unwrap().into_inner(), files))
+    }
+
+    /// Write the central directory at `central_offset` instead of after the last file, finishing the ZIP file.
+    ///
+    /// This is for embedding a ZIP file in a container format with a fixed layout. The offset may not
+    /// lie before the end of the last file; any gap up to it is left to the caller. Unlike `finish`
+    /// this keeps the writer, so the container can be completed through it. Afterwards no files can
+    /// be added, and `finish` only returns the inner writer.
+    pub fn finalize_at(&mut self, central_offset: u64) -> IoResult<()>
+    {
+        if self.inner.is_closed() || self.finalized { return writer_closed_error() }
+        try!(self.finish_file());
+        let end = try!(self.inner.get_plain().tell());
+        if central_offset < end
+        {
+            return Err(IoError
+                       {
+                           kind: io::InvalidInput,
+                           desc: "The central directory would overwrite file data",
+                           detail: Some(format!("offset {} is before the end of the data at {}", central_offset, end)),
+                       })
+        }
+        try!(self.inner.get_plain().seek(central_offset as i64, io::SeekSet));
+        self.finalize()
+    }
+
+    fn finalize(&mut self) -> IoResult<()>
+    {
+        if self.finalized { return Ok(()) }
+        try!(self.finish_file());
+        try!(self.write_final_central_directory());
+        self.inner.get_plain().commit_all_observed();
+        self.finalized = true;
+        // Make sure a buffered inner writer has written everything by the time finish returns
+        self.inner.get_plain().flush()
+    }
+
+    /// Write the central directory so that it ends no earlier than any provisional one.
+    ///
+    /// The final central directory may be shorter, for example after changing the comment or
+    /// replacing a file. Bytes of the provisional one left after the end record would hide it from
+    /// readers, so the directory is moved forward and the gap before it is zeroed.
+    /// Without provisional central directories, this writes the directory where it is.
+    fn write_final_central_directory(&mut self) -> IoResult<()>
+    {
+        loop
+        {
+            let central_start = try!(self.inner.get_plain().tell());
+            try!(self.write_central_directory());
+            let end = try!(self.inner.get_plain().tell());
+            if end >= self.provisional_end { return Ok(()) }
+
+            let writer = self.inner.get_plain();
+            try!(writer.seek(central_start as i64, io::SeekSet));
+            try!(writer.write(Vec::from_elem((self.provisional_end - end) as uint, 0u8).as_slice()));
+        }
+    }
+
+    fn write_central_directory(&mut self) -> IoResult<()>
+    {
+        {
+            let writer = self.inner.get_plain();
+
+            let central_start = try!(writer.tell());
+            for file in self.files.iter()
+            {
+                try!(writer_spec::write_central_directory_header(writer, file));
+            }
+            let central_size = try!(writer.tell()) - central_start;
+            let number_of_files = self.files.len() as u64;
+
+            let zip64 = self.zip64_mode == Zip64Always || number_of_files >= 0xFFFF
+                || central_start >= 0xFFFFFFFF || central_size >= 0xFFFFFFFF;
+            if zip64 && self.zip64_mode == Zip64Never { return too_large_error() }
+
+            if zip64
+            {
+                let zip64_footer_start = try!(writer.tell());
+                let zip64_footer = spec::Zip64CentralDirectoryEnd
+                {
+                    version_made_by: 45,
+                    version_needed: 45,
+                    disk_number: 0,
+                    disk_with_central_directory: 0,
+                    number_of_files_on_this_disk: number_of_files,
+                    number_of_files: number_of_files,
+                    central_directory_size: central_size,
+                    central_directory_offset: central_start,
+                };
+                try!(zip64_footer.write(writer));
+                try!(spec::Zip64CentralDirectoryEnd::write_locator(writer, zip64_footer_start));
+            }
+
+            // Values that do not fit are set to all ones, so readers look them up in the ZIP64 record
+            let footer = spec::CentralDirectoryEnd
+            {
+                disk_number: 0,
+                disk_with_central_directory: 0,
+                number_of_files_on_this_disk: if zip64 { 0xFFFF } else { number_of_files as u16 },
+                number_of_files: if zip64 { 0xFFFF } else { number_of_files as u16 },
+                central_directory_size: if zip64 { 0xFFFFFFFF } else { central_size as u32 },
+                central_directory_offset: if zip64 { 0xFFFFFFFF } else { central_start as u32 },
+                zip_file_comment: self.comment.clone(),
+            };
+
+            try!(footer.write(writer));
+        }
+
+        Ok(())
+    }
+}
+
+#[unsafe_destructor]
+impl<W: Writer+Seek> Drop for ZipWriter<W>
+{
+    fn drop(&mut self)
+    {
+        if !self.inner.is_closed()
+        {
+            match self.finalize()
+            {
+                Ok(_) => {},
+                Err(e) => warn!("ZipWriter drop failed: {}", e),
+            }
+        }
+    }
+}
+
+impl<W: Writer+Seek> GenericZipWriter<W>
+{
+    fn switch_to(&mut self, compression: compression::CompressionMethod, level: flate2::CompressionLevel) -> IoResult<()>
+    {
+        let bare = match mem::replace(self, Closed)
+        {
+            Storer(w) => w,
+            Deflater(w) => try!(w.finish()),
+            Closed => return writer_closed_error(),
+        };
+
+        *self = match compression
+        {
+            compression::Stored => Storer(bare),
+            compression::Deflated => Deflater(bare.deflate_encode(level)),
+            _ => return Err(IoError { kind: io::OtherIoError, desc: "Unsupported compression requested", detail: None }),
+        };
+
+        Ok(())
+    }
+
+    fn write(&mut self, buf: &[u8]) -> IoResult<()>
+    {
+        match *self
+        {
+            Storer(ref mut w) => w.write(buf),
+            Deflater(ref mut w) => w.write(buf),
+            Closed => writer_closed_error(),
+        }
+    }
+
+    fn is_closed(&self) -> bool
+    {
+        match *self
+        {
+            Closed => true,
+            _ => false,
+        }
+    }
+
+    fn get_plain(&mut self) -> &mut W
+    {
+        match *self
+        {
+            Storer(ref mut w) => w,
+            _ => panic!("Should have switched to stored beforehand"),
+        }
+    }
+
+    fn unwrap(self) -> W
+    {
+        match self
+        {
+            Storer(w) => w,
+            _ => panic!("Should have switched to stored beforehand"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test
+{
+    use compression;
+    use testutil;
+    use std::io;
+    use std::io::fs;
+    use std::io::net::pipe::UnixListener;
+    use flate2;
+    use crc32;
+    use time;
+    use std::mem;
+    use spec;
+    use std::cell::Cell;
+    use std::rc::Rc;
+
+    #[test]
+    fn directory_tree_is_added_without_following_symlinks()
+    {
+        let root = io::TempDir::new("zip-tree").unwrap();
+        fs::mkdir(&root.path().join("sub"), io::USER_DIR).unwrap();
+        io::File::create(&root.path().join("a.txt")).unwrap().write(b"a").unwrap();
+        io::File::create(&root.path().join("sub").join("b.txt")).unwrap().write(b"b").unwrap();
+        fs::symlink(&Path::new("a.txt"), &root.path().join("link")).unwrap();
+        // Following this link would recurse forever
+        fs::symlink(&Path::new(".."), &root.path().join("sub").join("loop")).unwrap();
+
+        let zip = testutil::open_zip(testutil::write_zip(|zip| zip.add_directory_tree(root.path(), compression::Deflated)));
+        let names: Vec<String> = zip.files().map(|file| file.file_name.clone()).collect();
+        assert_eq!(names, vec!["a.txt".to_string(), "link".to_string(), "sub/".to_string(),
+                               "sub/b.txt".to_string(), "sub/loop".to_string()]);
+        assert_eq!(zip.read_file_to_vec("sub/b.txt").unwrap().as_slice(), b"b");
+        assert!(zip.by_name("sub/").unwrap().unix_mode().unwrap() & 0o040000 != 0);
+
+        let link = zip.by_name("link").unwrap();
+        assert!(link.is_symlink());
+        assert_eq!(zip.read_file_to_vec("link").unwrap().as_slice(), b"a.txt");
+    }
+
+    #[test]
+    fn special_files_are_skipped_or_rejected()
+    {
+        let root = io::TempDir::new("zip-tree").unwrap();
+        io::File::create(&root.path().join("a.txt")).unwrap().write(b"a").unwrap();
+        let _socket = UnixListener::bind(&root.path().join("socket")).unwrap();
+
+        let zip = testutil::open_zip(testutil::write_zip(|zip| zip.add_directory_tree(root.path(), compression::Stored)));
+        assert_eq!(zip.len(), 1);
+        assert!(zip.by_name("a.txt").is_some());
+
+        let mut zip = super::ZipWriter::new(testutil::MemSink::new());
+        let error = zip.add_directory_tree_with(root.path(), compression::Stored, super::ErrorOnSpecialFiles).unwrap_err();
+        assert_eq!(error.kind, io::InvalidInput);
+    }
+
+    #[test]
+    fn small_file_is_stored()
+    {
+        let zip = testutil::open_zip(testutil::write_zip(|zip|
+        {
+            zip.set_stored_below(16);
+            try!(zip.start_file("small.txt", compression::Deflated));
+            try!(zip.write(b"abc"));
+            try!(zip.start_file("large.txt", compression::Deflated));
+            zip.write(Vec::from_elem(100, b'a').as_slice())
+        }));
+
+        let small = zip.by_name("small.txt").unwrap();
+        assert_eq!(small.compression_method, compression::Stored);
+        assert_eq!(zip.read_file(small).unwrap().read_to_end().unwrap().as_slice(), b"abc");
+        assert_eq!(zip.by_name("large.txt").unwrap().compression_method, compression::Deflated);
+    }
+
+    #[test]
+    fn start_file_on_closed_writer_fails()
+    {
+        let mut zip = super::ZipWriter::new(testutil::MemSink::new());
+        // Put the writer in the state it is left in once finished
+        zip.inner = super::Closed;
+
+        let error = zip.start_file("a.txt", compression::Stored).unwrap_err();
+        assert_eq!(error.kind, io::Closed);
+        assert!(zip.write(b"data").is_err());
+    }
+
+    #[test]
+    fn known_file_is_written_without_seeking()
+    {
+        let data = b"precomputed contents";
+        let mut zip = super::ZipWriter::new(testutil::MemSink::without_seek());
+        zip.write_file_known("known.txt", compression::Stored, crc32::update(0, data),
+                             data.len() as u64, data.len() as u64, data).unwrap();
+        let archive = zip.finish().unwrap().into_inner();
+
+        let zip = testutil::open_zip(archive);
+        let file = zip.by_name("known.txt").unwrap();
+        assert_eq!(zip.read_file(file).unwrap().read_to_end().unwrap().as_slice(), data);
+    }
+
+    #[test]
+    fn internal_attributes_round_trip()
+    {
+        let zip = testutil::open_zip(testutil::write_zip(|zip|
+        {
+            try!(zip.start_file("text.txt", compression::Stored));
+            try!(zip.set_internal_attributes(1));
+            zip.write(b"plain text")
+        }));
+        assert_eq!(zip.by_name("text.txt").unwrap().internal_attributes, 1);
+    }
+
+    #[test]
+    fn checksum_is_not_computed_when_disabled()
+    {
+        let mut zip = super::ZipWriter::new(testutil::MemSink::new());
+        zip.set_compute_crc(false);
+        zip.start_file("a.txt", compression::Stored).unwrap();
+        zip.write(b"some data").unwrap();
+        assert_eq!(zip.stats.crc32, 0);
+        assert_eq!(zip.stats.bytes_written, 9);
+
+        let zip = testutil::open_zip(zip.finish().unwrap().into_inner());
+        let file = zip.by_name("a.txt").unwrap();
+        assert_eq!(file.crc32, 0);
+        assert_eq!(file.uncompressed_size, 9);
+    }
+
+    #[test]
+    fn reproducible_output_is_identical()
+    {
+        let build = ||
+        {
+            testutil::write_zip(|zip|
+            {
+                zip.set_reproducible(time::strptime("2014-01-01 00:00:00", "%Y-%m-%d %H:%M:%S").unwrap());
+                try!(zip.start_file("a.txt", compression::Deflated));
+                try!(zip.write(b"aaaaaaaaaaaaaaaaaaaa"));
+                try!(zip.add_directory("dir"));
+                try!(zip.start_file("dir/b.txt", compression::Stored));
+                zip.write(b"b")
+            })
+        };
+        assert_eq!(build(), build());
+    }
+
+    fn recompress_source() -> Vec<u8>
+    {
+        testutil::write_zip(|zip|
+        {
+            let mut options = super::FileOptions::new(compression::Stored);
+            options.unix_mode = Some(0o100644);
+            try!(zip.add_extra_field_to_next_file(0x9999, b"kept"));
+            try!(zip.start_file_with_options("a.txt", options));
+            try!(zip.set_internal_attributes(1));
+            try!(zip.write(b"text text text text text text"));
+            try!(zip.start_file("b.bin", compression::Stored));
+            zip.write(b"\x00\x01\x02\x03")
+        })
+    }
+
+    #[test]
+    fn recompress_chooses_method_per_file()
+    {
+        let source = testutil::open_zip(recompress_source());
+        let mut zip = super::ZipWriter::new(testutil::MemSink::new());
+        zip.recompress_from(&source, |file|
+        {
+            if file.file_name.as_slice().ends_with(".txt") { compression::Deflated } else { compression::Stored }
+        }).unwrap();
+        let zip = testutil::open_zip(zip.finish().unwrap().into_inner());
+
+        let text = zip.by_name("a.txt").unwrap();
+        assert_eq!(text.compression_method, compression::Deflated);
+        assert_eq!(text.unix_mode(), Some(0o100644));
+        assert_eq!(text.internal_attributes, 1);
+        assert_eq!(text.extra_field.as_slice(), b"\x99\x99\x04\x00kept");
+        assert_eq!(zip.read_file_to_vec("a.txt").unwrap().as_slice(), b"text text text text text text");
+        assert_eq!(zip.by_name("b.bin").unwrap().compression_method, compression::Stored);
+        assert_eq!(zip.read_file_to_vec("b.bin").unwrap().as_slice(), b"\x00\x01\x02\x03");
+    }
+
+    #[test]
+    fn recompress_respects_version_made_by_override()
+    {
+        let source = testutil::open_zip(recompress_source());
+        let mut zip = super::ZipWriter::new(testutil::MemSink::new());
+        zip.set_version_made_by(0x0A14);
+        zip.recompress_from(&source, |_| compression::Deflated).unwrap();
+        let zip = testutil::open_zip(zip.finish().unwrap().into_inner());
+        assert!(zip.files().all(|file| file.version_made_by == 0x0A14));
+    }
+
+    #[test]
+    fn zip64_block_is_left_out_of_copied_extra_field()
+    {
+        let extra_field = b"\x01\x00\x08\x00stale 64\x99\x99\x04\x00kept";
+        let blocks = super::extra_field_blocks(extra_field);
+        assert_eq!(blocks, vec![(0x0001, b"stale 64".as_slice()), (0x9999, b"kept".as_slice())]);
+
+        let source = testutil::open_zip(testutil::write_zip(|zip|
+        {
+            zip.set_zip64(super::Zip64Always);
+            try!(zip.add_extra_field_to_next_file(0x9999, b"kept"));
+            try!(zip.start_file("a.txt", compression::Stored));
+            zip.write(b"a")
+        }));
+        let mut zip = super::ZipWriter::new(testutil::MemSink::new());
+        zip.recompress_from(&source, |_| compression::Stored).unwrap();
+        let data = zip.finish().unwrap().into_inner();
+        // Only the kept block, in the local and the central header
+        assert_eq!(testutil::positions(data.as_slice(), b"\x01\x00\x10\x00").len(), 0);
+        assert_eq!(testutil::positions(data.as_slice(), b"\x01\x00\x18\x00").len(), 0);
+        let zip = testutil::open_zip(data);
+        let file = zip.by_name("a.txt").unwrap();
+        assert!(!file.zip64);
+        assert_eq!(file.extra_field.as_slice(), b"\x99\x99\x04\x00kept");
+    }
+
+    #[test]
+    fn recompress_rejects_too_long_extra_field()
+    {
+        let source = testutil::open_zip(testutil::write_zip(|zip|
+        {
+            try!(zip.add_extra_field_to_next_file(0x9999, Vec::from_elem(65500, 0u8).as_slice()));
+            try!(zip.start_file("a.txt", compression::Stored));
+            zip.write(b"a")
+        }));
+        let mut zip = super::ZipWriter::new(testutil::MemSink::new());
+        // The ZIP64 block of the writer no longer fits
+        zip.set_zip64(super::Zip64Always);
+        let error = zip.recompress_from(&source, |_| compression::Stored).unwrap_err();
+        assert_eq!(error.desc, "Extra field is longer than 65535 bytes");
+        assert!(zip.files().is_empty());
+    }
+
+    #[test]
+    fn recompress_rejects_unsupported_file_before_writing()
+    {
+        let mut data = recompress_source();
+        // Mark the second file as LZMA compressed in the central directory
+        let second = testutil::positions(data.as_slice(), b"PK\x01\x02")[1];
+        testutil::put_le_u16(data.as_mut_slice(), second + 10, 14);
+        let source = testutil::open_zip(data);
+
+        let mut zip = super::ZipWriter::new(testutil::MemSink::new());
+        assert!(zip.recompress_from(&source, |_| compression::Deflated).is_err());
+        assert_eq!(zip.files().len(), 1);
+        assert_eq!(zip.files()[0].file_name.as_slice(), "a.txt");
+    }
+
+    #[test]
+    fn open_file_transitions()
+    {
+        let mut zip = super::ZipWriter::new(testutil::MemSink::new());
+        assert!(!zip.has_open_file());
+        zip.start_file("a.txt", compression::Stored).unwrap();
+        assert!(zip.has_open_file());
+        zip.add_directory("dir").unwrap();
+        assert!(!zip.has_open_file());
+        zip.start_file("dir/b.txt", compression::Deflated).unwrap();
+        assert!(zip.has_open_file());
+        zip.write_stored_slice("c.txt", b"c").unwrap();
+        assert!(!zip.has_open_file());
+    }
+
+    #[test]
+    fn large_stored_slice_round_trip()
+    {
+        let data: Vec<u8> = range(0u, 1 << 20).map(|i| (i % 251) as u8).collect();
+        let zip = testutil::open_zip(testutil::write_zip(|zip| zip.write_stored_slice("large.bin", data.as_slice())));
+
+        let file = zip.by_name("large.bin").unwrap();
+        assert_eq!(file.compression_method, compression::Stored);
+        assert_eq!(zip.read_file_to_vec("large.bin").unwrap(), data);
+    }
+
+    #[test]
+    fn incremental_directory_survives_interruption()
+    {
+        let sink = testutil::MemSink::new();
+        let flushed = sink.flushed();
+        let mut zip = super::ZipWriter::new(sink);
+        zip.set_incremental_eocd(true);
+        zip.start_file("complete.txt", compression::Stored).unwrap();
+        zip.write(b"complete").unwrap();
+        zip.start_file("partial.txt", compression::Stored).unwrap();
+        zip.write(b"never finished").unwrap();
+        // Simulate the process dying: the writer is neither finished nor dropped
+        unsafe { mem::forget(zip); }
+
+        let zip = testutil::open_zip(flushed.borrow().clone());
+        let names: Vec<String> = zip.files().map(|file| file.file_name.clone()).collect();
+        assert_eq!(names, vec!["complete.txt".to_string()]);
+        assert_eq!(zip.read_file_to_vec("complete.txt").unwrap().as_slice(), b"complete");
+    }
+
+    #[test]
+    fn shorter_final_comment_leaves_no_stale_bytes()
+    {
+        let mut zip = super::ZipWriter::new(testutil::MemSink::new());
+        zip.set_incremental_eocd(true);
+        zip.start_file("a.txt", compression::Stored).unwrap();
+        zip.write(b"a").unwrap();
+        zip.add_directory("dir").unwrap();
+        // The provisional directory carries the default comment
+        zip.set_comment("").unwrap();
+        let data = zip.finish().unwrap().into_inner();
+
+        let footer = spec::CentralDirectoryEnd::find_and_parse(&mut io::MemReader::new(data.clone())).unwrap();
+        assert_eq!(footer.zip_file_comment.len(), 0);
+        let zip = testutil::open_zip(data);
+        assert_eq!(zip.len(), 2);
+        assert_eq!(zip.read_file_to_vec("a.txt").unwrap().as_slice(), b"a");
+    }
+
+    #[test]
+    fn overwritten_duplicate_leaves_no_stale_bytes()
+    {
+        let first = testutil::write_zip(|zip|
+        {
+            try!(zip.start_file("a.txt", compression::Stored));
+            try!(zip.set_file_comment(String::from_char(200, 'c').as_slice()));
+            zip.write(b"first")
+        });
+        let second = testutil::stored_zip(&[("a.txt", b"2")]);
+
+        let mut zip = super::ZipWriter::new(testutil::MemSink::new());
+        zip.set_incremental_eocd(true);
+        zip.merge_from(&testutil::open_zip(first), super::OverwriteDuplicate).unwrap();
+        zip.merge_from(&testutil::open_zip(second), super::OverwriteDuplicate).unwrap();
+        let data = zip.finish().unwrap().into_inner();
+
+        assert!(spec::CentralDirectoryEnd::find_and_parse(&mut io::MemReader::new(data.clone())).is_ok());
+        let zip = testutil::open_zip(data);
+        assert_eq!(zip.len(), 1);
+        assert_eq!(zip.read_file_to_vec("a.txt").unwrap().as_slice(), b"2");
+    }
+
+    #[test]
+    fn over_length_comment_is_rejected()
+    {
+        let comment = String::from_char(70000, 'a');
+        let mut zip = super::ZipWriter::new(testutil::MemSink::new());
+        assert!(zip.set_comment(comment.as_slice()).is_err());
+        zip.start_file("a.txt", compression::Stored).unwrap();
+        assert!(zip.set_file_comment(comment.as_slice()).is_err());
+    }
+
+    #[test]
+    fn multibyte_comment_round_trip()
+    {
+        let data = testutil::write_zip(|zip|
+        {
+            try!(zip.set_comment("Ünïcödé ✓"));
+            try!(zip.start_file("a.txt", compression::Stored));
+            zip.set_file_comment("日本語のコメント")
+        });
+        let footer = spec::CentralDirectoryEnd::find_and_parse(&mut io::MemReader::new(data.clone())).unwrap();
+        assert_eq!(footer.zip_file_comment.as_slice(), "Ünïcödé ✓".as_bytes());
+
+        let zip = testutil::open_zip(data);
+        assert_eq!(zip.by_name("a.txt").unwrap().file_comment.as_slice(), "日本語のコメント");
+    }
+
+    #[test]
+    fn directory_name_gets_trailing_slash()
+    {
+        let zip = testutil::open_zip(testutil::write_zip(|zip|
+        {
+            try!(zip.add_directory("foo"));
+            zip.add_directory("bar/")
+        }));
+        let names: Vec<String> = zip.files().map(|file| file.file_name.clone()).collect();
+        assert_eq!(names, vec!["foo/".to_string(), "bar/".to_string()]);
+    }
+
+    #[test]
+    fn directory_and_file_with_same_base_name_coexist()
+    {
+        let zip = testutil::open_zip(testutil::write_zip(|zip|
+        {
+            try!(zip.add_directory("foo"));
+            try!(zip.start_file("foo", compression::Stored));
+            zip.write(b"file")
+        }));
+        assert_eq!(zip.len(), 2);
+        assert!(zip.by_name("foo/").is_some());
+        assert_eq!(zip.read_file_to_vec("foo").unwrap().as_slice(), b"file");
+    }
+
+    #[test]
+    fn observer_sees_all_written_data()
+    {
+        let observed = Rc::new(Cell::new(0u64));
+        let counter = observed.clone();
+        let zip = testutil::open_zip(testutil::write_zip(|zip|
+        {
+            let counter = counter.clone();
+            zip.set_uncompressed_observer(box move |&mut: data: &[u8]| counter.set(counter.get() + data.len() as u64));
+            try!(zip.start_file("a.txt", compression::Deflated));
+            for _ in range(0u, 100)
+            {
+                try!(zip.write(b"some data "));
+            }
+            Ok(())
+        }));
+        assert_eq!(observed.get(), zip.by_name("a.txt").unwrap().uncompressed_size);
+        assert_eq!(observed.get(), 1000);
+    }
+
+    #[test]
+    fn ratio_of_compressible_data_is_below_one()
+    {
+        let mut zip = super::ZipWriter::new(testutil::MemSink::new());
+        assert_eq!(zip.current_entry_ratio(), None);
+        zip.start_file("a.txt", compression::Deflated).unwrap();
+        assert_eq!(zip.current_entry_ratio(), None);
+
+        zip.write(Vec::from_elem(1 << 20, b'a').as_slice()).unwrap();
+        assert!(zip.current_entry_ratio().unwrap() < 1.0);
+    }
+
+    #[test]
+    fn raw_copy_renames_file()
+    {
+        let contents = Vec::from_elem(1000, b'b');
+        let source = testutil::open_zip(testutil::write_zip(|zip|
+        {
+            try!(zip.start_file("a/b.txt", compression::Deflated));
+            zip.write(contents.as_slice())
+        }));
+        let original = source.by_name("a/b.txt").unwrap();
+
+        let mut zip = super::ZipWriter::new(testutil::MemSink::new());
+        zip.raw_copy_file_rename(&source, original, "b.txt").unwrap();
+        let zip = testutil::open_zip(zip.finish().unwrap().into_inner());
+
+        assert!(zip.by_name("a/b.txt").is_none());
+        let renamed = zip.by_name("b.txt").unwrap();
+        assert_eq!(renamed.crc32, original.crc32);
+        assert_eq!(renamed.compressed_size, original.compressed_size);
+        assert_eq!(zip.read_file_to_vec("b.txt").unwrap(), contents);
+    }
+
+    #[test]
+    fn access_and_creation_times_round_trip()
+    {
+        let tm = |s: &str| time::strptime(s, "%Y-%m-%d %H:%M:%S").unwrap();
+        let mut options = super::FileOptions::new(compression::Stored);
+        options.last_modified_time = Some(tm("2014-03-04 05:06:08"));
+        options.last_access_time = Some(tm("2014-05-06 07:08:09"));
+        options.creation_time = Some(tm("2013-01-02 03:04:05"));
+        let data = testutil::write_zip(|zip|
+        {
+            try!(zip.start_file_with_options("a.txt", options.clone()));
+            zip.write(b"a")
+        });
+        // The local block holds all three times, the central one only the modification time
+        assert_eq!(testutil::positions(data.as_slice(), b"UT\x0d\x00\x07").len(), 1);
+        assert_eq!(testutil::positions(data.as_slice(), b"UT\x05\x00\x07").len(), 1);
+
+        let zip = testutil::open_zip(data);
+        let file = zip.by_name("a.txt").unwrap();
+        assert_eq!(file.last_modified_time.to_timespec(), tm("2014-03-04 05:06:08").to_timespec());
+        assert_eq!(file.last_access_time.unwrap().to_timespec(), tm("2014-05-06 07:08:09").to_timespec());
+        assert_eq!(file.creation_time.unwrap().to_timespec(), tm("2013-01-02 03:04:05").to_timespec());
+    }
+
+    #[test]
+    fn time_beyond_extended_timestamp_range_is_rejected()
+    {
+        let mut options = super::FileOptions::new(compression::Stored);
+        options.creation_time = Some(time::strptime("2100-01-01 00:00:00", "%Y-%m-%d %H:%M:%S").unwrap());
+        let mut zip = super::ZipWriter::new(testutil::MemSink::new());
+        let error = zip.start_file_with_options("a.txt", options).unwrap_err();
+        assert_eq!(error.desc, "Time does not fit in the extended timestamp");
+    }
+
+    #[test]
+    fn compression_level_per_file()
+    {
+        let text = Vec::from_elem(10000, b't');
+        let zip = testutil::open_zip(testutil::write_zip(|zip|
+        {
+            let mut best = super::FileOptions::new(compression::Deflated);
+            best.compression_level = flate2::Best;
+            try!(zip.start_file_with_options("best.txt", best));
+            try!(zip.write(text.as_slice()));
+
+            let mut fast = super::FileOptions::new(compression::Deflated);
+            fast.compression_level = flate2::Fast;
+            try!(zip.start_file_with_options("fast.txt", fast));
+            zip.write(text.as_slice())
+        }));
+        assert_eq!(zip.read_file_to_vec("best.txt").unwrap(), text);
+        assert_eq!(zip.read_file_to_vec("fast.txt").unwrap(), text);
+    }
+
+    #[test]
+    fn versions_can_be_overridden()
+    {
+        let zip = testutil::open_zip(testutil::write_zip(|zip|
+        {
+            // Info-ZIP 3.0 on Unix
+            zip.set_version_made_by(0x031E);
+            zip.set_version_needed(10);
+            try!(zip.start_file("a.txt", compression::Deflated));
+            zip.write(b"aaaaaaaaaa")
+        }));
+        let file = zip.by_name("a.txt").unwrap();
+        assert_eq!(file.version_made_by, 0x031E);
+        assert_eq!(file.version_needed, 10);
+    }
+
+    #[test]
+    fn compressed_size_matches_emitted_bytes()
+    {
+        let data = testutil::write_zip(|zip|
+        {
+            try!(zip.start_file("a.txt", compression::Deflated));
+            zip.write(Vec::from_elem(1000, b'a').as_slice())
+        });
+        let directory_start = testutil::positions(data.as_slice(), b"PK\x01\x02")[0] as u64;
+        let zip = testutil::open_zip(data);
+        let file = zip.by_name("a.txt").unwrap();
+        assert_eq!(file.compressed_size, directory_start - file.data_start);
+        assert!(file.compressed_size < 1000);
+    }
+
+    #[test]
+    fn index_entry_lists_earlier_files()
+    {
+        let zip = testutil::open_zip(testutil::write_zip(|zip|
+        {
+            try!(zip.start_file("a.txt", compression::Deflated));
+            try!(zip.write(b"aaa"));
+            try!(zip.start_file("b.txt", compression::Stored));
+            try!(zip.write(b"bbbbb"));
+            zip.write_index_entry("index.txt")
+        }));
+
+        let index = String::from_utf8(zip.read_file_to_vec("index.txt").unwrap()).unwrap();
+        let expected = format!("a.txt\t3\t{:08x}\nb.txt\t5\t{:08x}\n",
+                               crc32::update(0, b"aaa"), crc32::update(0, b"bbbbb"));
+        assert_eq!(index, expected);
+        assert_eq!(zip.by_name("index.txt").unwrap().compression_method, compression::Stored);
+    }
+
+    #[test]
+    fn repeated_byte_file_has_full_size()
+    {
+        let count = 1024 * 1024 + 7;
+        let zip = testutil::open_zip(testutil::write_zip(|zip|
+        {
+            zip.write_repeated("zeros.bin", compression::Deflated, b'z', count)
+        }));
+
+        let file = zip.by_name("zeros.bin").unwrap();
+        assert_eq!(file.uncompressed_size, count);
+        assert!(file.compressed_size < count / 100);
+        let contents = zip.read_file_to_vec("zeros.bin").unwrap();
+        assert_eq!(contents.len() as u64, count);
+        assert_eq!(contents[700 * 1024], b'z');
+        assert!(contents.iter().all(|&byte| byte == b'z'));
+    }
+
+    #[test]
+    fn utc_time_round_trips()
+    {
+        let before = time::get_time().sec;
+        let zip = testutil::open_zip(testutil::write_zip(|zip|
+        {
+            zip.set_use_utc(true);
+            try!(zip.start_file("a.txt", compression::Stored));
+            zip.write(b"a")
+        }));
+        let after = time::get_time().sec;
+
+        // MS-DOS times have a resolution of two seconds
+        let modified = zip.by_name("a.txt").unwrap().modified_unix_time_utc();
+        assert!(modified >= before - 2 && modified <= after, "{} not in {}..{}", modified, before, after);
+    }
+
+    #[test]
+    fn merge_resolves_duplicates_by_policy()
+    {
+        let merged = |policy|
+        {
+            let first = io::MemReader::new(testutil::stored_zip(&[("a.txt", b"first a"), ("b.txt", b"b")]));
+            let second = io::MemReader::new(testutil::stored_zip(&[("a.txt", b"second a"), ("c.txt", b"c")]));
+            let sink = super::merge_archives(vec![first, second], testutil::MemSink::new(), policy).unwrap();
+            testutil::open_zip(sink.into_inner())
+        };
+        let names = |zip: &::reader::ZipReader<io::MemReader>|
+        {
+            zip.files().map(|file| file.file_name.clone()).collect::<Vec<String>>()
+        };
+
+        let zip = merged(super::SkipDuplicate);
+        assert_eq!(names(&zip), vec!["a.txt".to_string(), "b.txt".to_string(), "c.txt".to_string()]);
+        assert_eq!(zip.read_file_to_vec("a.txt").unwrap(), b"first a".to_vec());
+
+        let zip = merged(super::OverwriteDuplicate);
+        assert_eq!(names(&zip), vec!["b.txt".to_string(), "a.txt".to_string(), "c.txt".to_string()]);
+        assert_eq!(zip.read_file_to_vec("a.txt").unwrap(), b"second a".to_vec());
+
+        let zip = merged(super::RenameDuplicate);
+        assert_eq!(names(&zip), vec!["a.txt".to_string(), "b.txt".to_string(), "a (1).txt".to_string(),
+                                     "c.txt".to_string()]);
+        assert_eq!(zip.read_file_to_vec("a.txt").unwrap(), b"first a".to_vec());
+        assert_eq!(zip.read_file_to_vec("a (1).txt").unwrap(), b"second a".to_vec());
+    }
+
+    #[test]
+    fn unknown_extra_field_survives_raw_copy()
+    {
+        // NTFS block: reserved, then attribute tag 0x0002 with four bytes of data
+        let ntfs = b"\x00\x00\x00\x00\x02\x00\x04\x00\xde\xad\xbe\xef";
+        let mut block = b"\x0a\x00\x0c\x00".to_vec();
+        block.push_all(ntfs);
+
+        let source = testutil::open_zip(testutil::write_zip(|zip|
+        {
+            try!(zip.add_extra_field_to_next_file(0x000a, ntfs));
+            try!(zip.start_file("a.txt", compression::Stored));
+            zip.write(b"a")
+        }));
+        assert_eq!(source.by_name("a.txt").unwrap().extra_field, block);
+
+        let copy = testutil::open_zip(testutil::write_zip(|zip|
+        {
+            zip.raw_copy_file(&source, source.by_name("a.txt").unwrap())
+        }));
+        let file = copy.by_name("a.txt").unwrap();
+        assert_eq!(file.extra_field, block);
+        assert_eq!(copy.read_file_to_vec("a.txt").unwrap(), b"a".to_vec());
+    }
+
+    #[test]
+    fn dry_run_reports_final_sizes_of_all_files()
+    {
+        fn add_files<W: Writer+Seek>(zip: &mut super::ZipWriter<W>) -> io::IoResult<()>
+        {
+            try!(zip.start_file("a.txt", compression::Stored));
+            try!(zip.write(b"aaaa"));
+            try!(zip.start_file("b.txt", compression::Deflated));
+            zip.write(Vec::from_elem(5000, b'b').as_slice())
+        }
+
+        let mut dry_run = super::ZipWriter::new(::util::DryRunSink::new());
+        add_files(&mut dry_run).unwrap();
+        let (sink, files) = dry_run.finish_with_files().unwrap();
+
+        let data = testutil::write_zip(|zip| add_files(zip));
+        assert_eq!(sink.len(), data.len() as u64);
+        let zip = testutil::open_zip(data);
+        assert_eq!(files.len(), 2);
+        for (predicted, written) in files.iter().zip(zip.files())
+        {
+            assert_eq!(predicted.file_name, written.file_name);
+            assert_eq!(predicted.compressed_size, written.compressed_size);
+            assert_eq!(predicted.uncompressed_size, written.uncompressed_size);
+            assert_eq!(predicted.crc32, written.crc32);
+        }
+        assert_eq!(files[1].uncompressed_size, 5000);
+    }
+
+    #[test]
+    fn added_file_keeps_mode_and_modified_time()
+    {
+        let root = io::TempDir::new("zip-add-file").unwrap();
+        let path = root.path().join("script.sh");
+        io::File::create(&path).unwrap().write(b"#!/bin/sh\n").unwrap();
+        fs::chmod(&path, io::FilePermission::from_bits_truncate(0o750)).unwrap();
+        fs::change_file_times(&path, 1400000000000, 1400000000000).unwrap();
+
+        let zip = testutil::open_zip(testutil::write_zip(|zip| zip.add_file(&path, "bin/script.sh", compression::Deflated)));
+        let file = zip.by_name("bin/script.sh").unwrap();
+        assert_eq!(file.unix_mode(), Some(0o100750));
+        assert_eq!(file.modified_unix_time(), 1400000000);
+        assert_eq!(zip.read_file_to_vec("bin/script.sh").unwrap().as_slice(), b"#!/bin/sh\n");
+
+        let mut zip = super::ZipWriter::new(testutil::MemSink::new());
+        let error = zip.add_file(root.path(), "dir", compression::Stored).unwrap_err();
+        assert_eq!(error.desc, "Path is not a regular file");
+    }
+
+    #[test]
+    fn extra_field_length_includes_blocks_added_by_writer()
+    {
+        let user_data = Vec::from_elem(65535 - 4 - 10, 0u8);
+
+        let mut zip = super::ZipWriter::new(testutil::MemSink::new());
+        zip.add_extra_field_to_next_file(0x9999, user_data.as_slice()).unwrap();
+        assert_eq!(zip.add_extra_field_to_next_file(0x9998, b"too much").unwrap_err().desc,
+                   "Extra field is longer than 65535 bytes");
+        zip.set_zip64(super::Zip64Always);
+        assert_eq!(zip.start_file("zip64.txt", compression::Stored).unwrap_err().desc,
+                   "Extra field is longer than 65535 bytes");
+
+        zip.set_zip64(super::Zip64Auto);
+        zip.set_alignment(4096);
+        zip.add_extra_field_to_next_file(0x9999, user_data.as_slice()).unwrap();
+        assert_eq!(zip.start_file("aligned.txt", compression::Stored).unwrap_err().desc,
+                   "Extra field is longer than 65535 bytes");
+
+        // The blocks only apply to the next file, so they are gone after it failed
+        zip.start_file("plain.txt", compression::Stored).unwrap();
+        let zip = testutil::open_zip(zip.finish().unwrap().into_inner());
+        let names: Vec<String> = zip.files().map(|file| file.file_name.clone()).collect();
+        assert_eq!(names, vec!["plain.txt".to_string()]);
+        assert!(zip.by_name("plain.txt").unwrap().extra_field.is_empty());
+    }
+
+    #[test]
+    fn finish_flushes_complete_zip()
+    {
+        let sink = testutil::MemSink::new();
+        let flushed = sink.flushed();
+        let mut zip = super::ZipWriter::new(sink);
+        zip.start_file("a.txt", compression::Deflated).unwrap();
+        zip.write(b"aaaaaaaa").unwrap();
+        let data = zip.finish().unwrap().into_inner();
+
+        // A buffering writer would have passed on everything up to the last flush
+        assert_eq!(*flushed.borrow(), data);
+        let zip = testutil::open_zip(flushed.borrow().clone());
+        assert_eq!(zip.read_file_to_vec("a.txt").unwrap().as_slice(), b"aaaaaaaa");
+    }
+
+    #[test]
+    fn gp_flag_is_written_to_both_headers()
+    {
+        let data = testutil::write_zip(|zip|
+        {
+            try!(zip.start_file("a.txt", compression::Stored));
+            try!(zip.set_gp_flag(13, true));
+            // Managed by this crate, so ignored
+            try!(zip.set_gp_flag(3, true));
+            zip.write(b"a")
+        });
+        let local = testutil::positions(data.as_slice(), b"PK\x03\x04")[0];
+        assert_eq!(data.slice(local + 6, local + 8), b"\x00\x20");
+
+        let zip = testutil::open_zip(data);
+        let file = zip.by_name("a.txt").unwrap();
+        assert_eq!(file.flags, 1 << 13);
+        assert!(!file.using_data_descriptor);
+
+        let mut zip = super::ZipWriter::new(testutil::MemSink::new());
+        assert_eq!(zip.set_gp_flag(16, true).unwrap_err().desc, "General purpose flags only have 16 bits");
+    }
+
+    #[test]
+    fn file_failing_verification_is_left_out()
+    {
+        fn corrupt(data: &mut [u8])
+        {
+            let middle = data.len() / 2;
+            data[middle] ^= 0x55;
+        }
+
+        let mut zip = super::ZipWriter::new(testutil::MemSink::new());
+        zip.set_verify_on_write(true);
+        zip.start_file("good.txt", compression::Deflated).unwrap();
+        zip.write(Vec::from_elem(1000, b'g').as_slice()).unwrap();
+        zip.capture_fault = Some(corrupt);
+        zip.start_file("bad.txt", compression::Deflated).unwrap();
+        for i in range(0u, 1000)
+        {
+            zip.write(format!("line {}\n", i).as_bytes()).unwrap();
+        }
+        let error = zip.start_file("after.txt", compression::Stored).unwrap_err();
+        assert_eq!(error.detail, Some("bad.txt".to_string()));
+
+        // The short file ends well before the discarded data, which must not be left after the end record
+        zip.capture_fault = None;
+        zip.start_file("after.txt", compression::Stored).unwrap();
+        zip.write(b"after").unwrap();
+        let zip = testutil::open_zip(zip.finish().unwrap().into_inner());
+        let names: Vec<String> = zip.files().map(|file| file.file_name.clone()).collect();
+        assert_eq!(names, vec!["good.txt".to_string(), "after.txt".to_string()]);
+        assert_eq!(zip.read_file_to_vec("good.txt").unwrap(), Vec::from_elem(1000, b'g'));
+        assert_eq!(zip.read_file_to_vec("after.txt").unwrap().as_slice(), b"after");
+    }
+
+    #[test]
+    fn verification_failure_in_finish_leaves_valid_zip()
+    {
+        fn corrupt(data: &mut [u8])
+        {
+            let middle = data.len() / 2;
+            data[middle] ^= 0x55;
+        }
+
+        let sink = testutil::MemSink::new();
+        let flushed = sink.flushed();
+        let mut zip = super::ZipWriter::new(sink);
+        zip.set_verify_on_write(true);
+        zip.start_file("good.txt", compression::Stored).unwrap();
+        zip.write(b"good").unwrap();
+        zip.capture_fault = Some(corrupt);
+        zip.start_file("bad.txt", compression::Deflated).unwrap();
+        for i in range(0u, 1000)
+        {
+            zip.write(format!("line {}\n", i).as_bytes()).unwrap();
+        }
+        // Dropping the writer after the failure writes the central directory without the bad file
+        assert!(zip.finish().is_err());
+
+        let zip = testutil::open_zip(flushed.borrow().clone());
+        let names: Vec<String> = zip.files().map(|file| file.file_name.clone()).collect();
+        assert_eq!(names, vec!["good.txt".to_string()]);
+    }
+
+    #[test]
+    fn version_needed_follows_features()
+    {
+        let zip = testutil::open_zip(testutil::write_zip(|zip|
+        {
+            try!(zip.start_file("stored.txt", compression::Stored));
+            try!(zip.write(b"a"));
+            try!(zip.start_file("deflated.txt", compression::Deflated));
+            try!(zip.write(b"a"));
+            try!(zip.add_directory("dir"));
+            zip.set_zip64(super::Zip64Always);
+            try!(zip.start_file("zip64.txt", compression::Stored));
+            zip.write(b"a")
+        }));
+        assert_eq!(zip.by_name("stored.txt").unwrap().version_needed, 10);
+        assert_eq!(zip.by_name("deflated.txt").unwrap().version_needed, 20);
+        assert_eq!(zip.by_name("dir/").unwrap().version_needed, 20);
+        assert_eq!(zip.by_name("zip64.txt").unwrap().version_needed, 45);
+        assert_eq!(zip.required_version(), 45);
+    }
+
+    #[test]
+    fn raw_rebuild_is_byte_identical()
+    {
+        let mtime = time::strptime("2012-03-04 05:06:08", "%Y-%m-%d %H:%M:%S").unwrap();
+        let original = testutil::write_zip(|zip|
+        {
+            zip.set_reproducible(mtime);
+            try!(zip.start_file("a.txt", compression::Deflated));
+            try!(zip.write(b"aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa"));
+            try!(zip.add_directory("dir"));
+            try!(zip.start_file("dir/b.bin", compression::Stored));
+            zip.write(b"\x00\x01\x02\x03")
+        });
+
+        let source = testutil::open_zip(original.clone());
+        let rebuilt = testutil::write_zip(|zip|
+        {
+            zip.set_reproducible(mtime);
+            for entry in source.raw_entries()
+            {
+                let (file, data) = try!(entry);
+                try!(zip.raw_write_file(&file, data.as_slice()));
+            }
+            Ok(())
+        });
+        assert_eq!(rebuilt, original);
+    }
+
+    #[test]
+    fn zip64_modes_on_small_and_large_files()
+    {
+        let small_zip = |mode|
+        {
+            testutil::write_zip(|zip|
+            {
+                zip.set_zip64(mode);
+                try!(zip.start_file("a.txt", compression::Stored));
+                zip.write(b"a")
+            })
+        };
+
+        let data = small_zip(super::Zip64Auto);
+        assert!(testutil::positions(data.as_slice(), b"PK\x06\x06").is_empty());
+        assert_eq!(testutil::open_zip(data).by_name("a.txt").unwrap().version_needed, 10);
+
+        let data = small_zip(super::Zip64Never);
+        assert!(testutil::positions(data.as_slice(), b"PK\x06\x06").is_empty());
+
+        let data = small_zip(super::Zip64Always);
+        assert_eq!(testutil::positions(data.as_slice(), b"PK\x06\x06").len(), 1);
+        let local = testutil::positions(data.as_slice(), b"PK\x03\x04")[0];
+        assert_eq!(data.slice(local + 18, local + 26), b"\xff\xff\xff\xff\xff\xff\xff\xff");
+        let zip = testutil::open_zip(data);
+        let file = zip.by_name("a.txt").unwrap();
+        assert_eq!((file.compressed_size, file.uncompressed_size, file.version_needed), (1, 1, 45));
+        assert_eq!(zip.read_file_to_vec("a.txt").unwrap().as_slice(), b"a");
+
+        // A file claiming more than 4 GiB of uncompressed data
+        let mut large = testutil::single_file("large.bin", compression::Stored, b"x");
+        large.uncompressed_size = 5 << 30;
+        let mut zip = super::ZipWriter::new(testutil::MemSink::new());
+        zip.set_zip64(super::Zip64Never);
+        assert_eq!(zip.raw_write_file(&large, b"x").unwrap_err().desc, "Value does not fit in a ZIP file without ZIP64");
+        zip.set_zip64(super::Zip64Auto);
+        zip.raw_write_file(&large, b"x").unwrap();
+        let zip = testutil::open_zip(zip.finish().unwrap().into_inner());
+        assert_eq!(zip.by_name("large.bin").unwrap().uncompressed_size, 5 << 30);
+    }
+
+    #[test]
+    fn stored_data_is_aligned()
+    {
+        let data = testutil::write_zip(|zip|
+        {
+            zip.set_alignment(512);
+            try!(zip.start_file("a.txt", compression::Stored));
+            try!(zip.write(b"first"));
+            try!(zip.start_file("name of odd length.bin", compression::Stored));
+            try!(zip.write(b"second"));
+            try!(zip.start_file("deflated.txt", compression::Deflated));
+            try!(zip.write(b"third"));
+            try!(zip.start_file("b.bin", compression::Stored));
+            zip.write(b"fourth")
+        });
+
+        let zip = testutil::open_zip(data);
+        for name in ["a.txt", "name of odd length.bin", "b.bin"].iter()
+        {
+            let file = zip.by_name(*name).unwrap();
+            assert_eq!(file.data_start % 512, 0);
+        }
+        assert!(zip.by_name("deflated.txt").unwrap().data_start % 512 != 0);
+        assert_eq!(zip.read_file_to_vec("b.bin").unwrap().as_slice(), b"fourth");
+    }
+
+    #[test]
+    fn ntfs_host_with_readonly_attribute()
+    {
+        let zip = testutil::open_zip(testutil::write_zip(|zip|
+        {
+            zip.set_host_system(::types::HostNtfs);
+            try!(zip.start_file("readonly.txt", compression::Stored));
+            // FILE_ATTRIBUTE_READONLY | FILE_ATTRIBUTE_ARCHIVE
+            try!(zip.set_external_attributes(0x21));
+            zip.write(b"a")
+        }));
+        let file = zip.by_name("readonly.txt").unwrap();
+        assert_eq!(file.host_system(), Some(::types::HostNtfs));
+        assert_eq!(file.version_made_by >> 8, 11);
+        assert_eq!(file.external_attributes, 0x21);
+        assert_eq!(file.unix_mode(), None);
+
+        let mut zip = super::ZipWriter::new(testutil::MemSink::new());
+        zip.set_host_system(::types::HostNtfs);
+        let mut options = super::FileOptions::new(compression::Stored);
+        options.unix_mode = Some(0o100644);
+        let error = zip.start_file_with_options("a.txt", options).unwrap_err();
+        assert_eq!(error.desc, "A Unix mode needs the Unix host system");
+    }
+
+    #[test]
+    fn archive_observer_sees_output_in_order()
+    {
+        for &incremental in [false, true].iter()
+        {
+            let observed = Rc::new(::std::cell::RefCell::new(Vec::new()));
+
+            let mut zip = super::ZipWriter::new(testutil::MemSink::new());
+            zip.set_incremental_eocd(incremental);
+            {
+                let observed = observed.clone();
+                zip.set_archive_observer(box move |&mut: data: &[u8]| observed.borrow_mut().push_all(data)).unwrap();
+            }
+
+            zip.start_file("a.bin", compression::Stored).unwrap();
+            zip.write(Vec::from_elem(10000, b'a').as_slice()).unwrap();
+            zip.start_file("b.txt", compression::Deflated).unwrap();
+            // Passed on once the first file is finished, with its updated local header
+            assert_eq!(observed.borrow().len(), zip.files[1].header_start as uint);
+            zip.write(b"bbbbbbbbbbbbbbbb").unwrap();
+            let data = zip.finish().unwrap().into_inner();
+
+            assert_eq!(*observed.borrow(), data);
+        }
+    }
+
+    #[test]
+    fn write_buffer_reduces_inner_writes()
+    {
+        struct WriteCounter
+        {
+            sink: testutil::MemSink,
+            writes: Rc<Cell<uint>>,
+        }
+        impl Writer for WriteCounter
+        {
+            fn write(&mut self, buf: &[u8]) -> io::IoResult<()>
+            {
+                self.writes.set(self.writes.get() + 1);
+                self.sink.write(buf)
+            }
+        }
+        impl Seek for WriteCounter
+        {
+            fn tell(&self) -> io::IoResult<u64> { self.sink.tell() }
+            fn seek(&mut self, pos: i64, style: io::SeekStyle) -> io::IoResult<()> { self.sink.seek(pos, style) }
+        }
+
+        let write_bytes = |buffer_size: uint|
+        {
+            let writes = Rc::new(Cell::new(0u));
+            let mut zip = super::ZipWriter::new(WriteCounter { sink: testutil::MemSink::new(), writes: writes.clone() });
+            zip.set_write_buffer_size(buffer_size);
+            zip.start_file("bytes.bin", compression::Stored).unwrap();
+            for i in range(0u, 10000)
+            {
+                zip.write(&[i as u8]).unwrap();
+            }
+            let data = zip.finish().unwrap().sink.into_inner();
+            let zip = testutil::open_zip(data);
+            let contents = zip.read_file_to_vec("bytes.bin").unwrap();
+            assert_eq!(contents.len(), 10000);
+            assert_eq!(contents[9999], (9999u % 256) as u8);
+            writes.get()
+        };
+
+        let unbuffered = write_bytes(0);
+        let buffered = write_bytes(4096);
+        assert!(unbuffered >= 10000);
+        assert!(buffered < 100, "{} writes", buffered);
+    }
+
+    #[test]
+    fn central_directory_is_written_at_given_offset()
+    {
+        let mut zip = super::ZipWriter::new(testutil::MemSink::new());
+        zip.start_file("a.txt", compression::Stored).unwrap();
+        zip.write(b"aaaa").unwrap();
+        assert_eq!(zip.finalize_at(10).unwrap_err().desc, "The central directory would overwrite file data");
+        zip.finalize_at(4096).unwrap();
+        assert_eq!(zip.start_file("b.txt", compression::Stored).unwrap_err().kind, io::Closed);
+        // Finishing does not write the central directory again
+        let data = zip.finish().unwrap().into_inner();
+
+        assert_eq!(testutil::positions(data.as_slice(), b"PK\x01\x02"), vec![4096]);
+        assert_eq!(testutil::positions(data.as_slice(), b"PK\x05\x06").len(), 1);
+        let zip = testutil::open_zip(data.clone());
+        assert_eq!(zip.read_file_to_vec("a.txt").unwrap().as_slice(), b"aaaa");
+
+        // Embedded after the header of a container
+        let mut container = b"CONTAINER HEADER".to_vec();
+        container.push_all(data.as_slice());
+        let zip = ::reader::ZipReader::new_with_offset(io::MemReader::new(container), 16).unwrap();
+        assert_eq!(zip.read_file_to_vec("a.txt").unwrap().as_slice(), b"aaaa");
+    }
+
+    #[test]
+    fn duplicate_names_are_rejected_when_checked()
+    {
+        let source = testutil::open_zip(testutil::stored_zip(&[("raw.txt", b"raw")]));
+        let (raw_file, raw_data) = source.raw_entries().next().unwrap().unwrap();
+
+        let mut zip = super::ZipWriter::new(testutil::MemSink::new());
+        zip.set_check_duplicate_names(true);
+        zip.start_file("a.txt", compression::Stored).unwrap();
+        zip.write(b"first").unwrap();
+        // The file being written is already taken
+        let err = zip.start_file("a.txt", compression::Deflated).unwrap_err();
+        assert_eq!(err.kind, io::PathAlreadyExists);
+        assert_eq!(err.detail, Some("a.txt".to_string()));
+        zip.start_file("b.txt", compression::Stored).unwrap();
+        assert_eq!(zip.write_file_known("b.txt", compression::Stored, 0, 0, 0, b"").unwrap_err().kind, io::PathAlreadyExists);
+        zip.raw_write_file(&raw_file, raw_data.as_slice()).unwrap();
+        assert_eq!(zip.raw_write_file(&raw_file, raw_data.as_slice()).unwrap_err().kind, io::PathAlreadyExists);
+        // A directory conflicts with a file of the same name
+        assert_eq!(zip.add_directory("a.txt").unwrap_err().kind, io::PathAlreadyExists);
+        zip.add_directory("dir").unwrap();
+        assert_eq!(zip.start_file("dir", compression::Stored).unwrap_err().kind, io::PathAlreadyExists);
+
+        assert_eq!(zip.by_name("raw.txt").unwrap().crc32, raw_file.crc32);
+        assert!(zip.by_name("c.txt").is_none());
+        let zip = testutil::open_zip(zip.finish().unwrap().into_inner());
+        assert_eq!(zip.len(), 4);
+        assert_eq!(zip.read_file_to_vec("a.txt").unwrap().as_slice(), b"first");
+    }
+
+    #[test]
+    fn duplicate_names_coexist_by_default()
+    {
+        let mut zip = super::ZipWriter::new(testutil::MemSink::new());
+        zip.start_file("a.txt", compression::Stored).unwrap();
+        zip.write(b"first").unwrap();
+        zip.write_file_known("a.txt", compression::Stored, crc32::update(0, b"second"), 6, 6, b"second").unwrap();
+        zip.add_directory("a.txt").unwrap();
+
+        // The index points to the last file with the name
+        assert_eq!(zip.by_name("a.txt").unwrap().uncompressed_size, 6);
+        assert!(zip.by_name("a.txt/").is_some());
+        let zip = testutil::open_zip(zip.finish().unwrap().into_inner());
+        assert_eq!(zip.len(), 3);
     }
 }
@@ -1,23 +1,40 @@
 use std::io;
-use std::io::IoResult;
+use std::io::{IoResult, IoError};
 use types::ZipFile;
 use spec;
 use util;
 
-pub fn write_local_file_header<T: Writer>(writer: &mut T, file: &ZipFile) -> IoResult<()>
+static ZIP64_LIMIT : u64 = 0xFFFFFFFF;
+
+/// True if a size or the offset of the file does not fit in the regular headers.
+pub fn needs_zip64(file: &ZipFile) -> bool
 {
+    file.compressed_size >= ZIP64_LIMIT || file.uncompressed_size >= ZIP64_LIMIT || file.header_start >= ZIP64_LIMIT
+}
+
+/// Write the local header of the file, padded so that its data starts at a multiple of `alignment`.
+///
+/// An alignment of 0 or 1 adds no padding.
+pub fn write_local_file_header<T: Writer>(writer: &mut T, file: &ZipFile, alignment: u16) -> IoResult<()>
+{
+    let mut extra_field = try!(build_extra_field(file, false));
+    if alignment > 1
+    {
+        let data_start = file.header_start + 30 + file.file_name.as_bytes().len() as u64 + extra_field.len() as u64;
+        try!(write_alignment_padding(&mut extra_field, data_start, alignment));
+    }
+    try!(check_extra_field_length(extra_field.as_slice()));
+
     try!(writer.write_le_u32(spec::LOCAL_FILE_HEADER_SIGNATURE));
-    try!(writer.write_le_u16(20));
-    let flag = if !file.file_name.is_ascii() { 1u16 << 11 } else { 0 };
-    try!(writer.write_le_u16(flag));
+    try!(writer.write_le_u16(file.version_needed));
+    try!(writer.write_le_u16(general_purpose_flags(file, false)));
     try!(writer.write_le_u16(file.compression_method as u16));
-    try!(writer.write_le_u16(util::tm_to_msdos_time(file.last_modified_time)));
-    try!(writer.write_le_u16(util::tm_to_msdos_date(file.last_modified_time)));
+    let (msdos_time, msdos_date) = util::tm_to_msdos_datetime(file.last_modified_time);
+    try!(writer.write_le_u16(msdos_time));
+    try!(writer.write_le_u16(msdos_date));
     try!(writer.write_le_u32(file.crc32));
-    try!(writer.write_le_u32(file.compressed_size as u32));
-    try!(writer.write_le_u32(file.uncompressed_size as u32));
+    try!(write_local_sizes(writer, file));
     try!(writer.write_le_u16(file.file_name.as_bytes().len() as u16));
-    let extra_field = try!(build_extra_field(file));
     try!(writer.write_le_u16(extra_field.len() as u16));
     try!(writer.write(file.file_name.as_bytes()));
     try!(writer.write(extra_field.as_slice()));
@@ -25,46 +42,212 @@ pub fn write_local_file_header<T: Writer>(writer: &mut T, file: &ZipFile) -> IoR
     Ok(())
 }
 
+/// The complete extra field, including the ZIP64, timestamp and padding blocks, must fit its u16 length.
+fn check_extra_field_length(extra_field: &[u8]) -> IoResult<()>
+{
+    if extra_field.len() > ::std::u16::MAX as uint
+    {
+        return Err(IoError
+                   {
+                       kind: io::InvalidInput,
+                       desc: "Extra field is longer than 65535 bytes",
+                       detail: Some(format!("{} bytes", extra_field.len())),
+                   })
+    }
+    Ok(())
+}
+
+/// Append an alignment block (0xD935, as used by zipalign) that moves `data_start` to a multiple of `alignment`.
+fn write_alignment_padding(extra_field: &mut Vec<u8>, data_start: u64, alignment: u16) -> IoResult<()>
+{
+    // Block header plus the alignment itself
+    static MIN_BLOCK_SIZE : u64 = 6;
+    let alignment = alignment as u64;
+    let padding = (alignment - (data_start + MIN_BLOCK_SIZE) % alignment) % alignment;
+
+    let mut writer = io::MemWriter::new();
+    try!(writer.write_le_u16(0xD935));
+    try!(writer.write_le_u16((2 + padding) as u16));
+    try!(writer.write_le_u16(alignment as u16));
+    try!(writer.write(Vec::from_elem(padding as uint, 0u8).as_slice()));
+    extra_field.push_all(writer.get_ref());
+    Ok(())
+}
+
+/// Update the local header of the file with its final checksum, sizes and version needed.
+///
+/// The changed fields are written with a single write, which also rewrites the unchanged name and
+/// lengths between them when the file has a ZIP64 extra field.
 pub fn update_local_file_header<T: Writer+Seek>(writer: &mut T, file: &ZipFile) -> IoResult<()>
 {
-    static CRC32_OFFSET : i64 = 14;
-    try!(writer.seek(file.header_start as i64 + CRC32_OFFSET, io::SeekSet));
-    try!(writer.write_le_u32(file.crc32));
-    try!(writer.write_le_u32(file.compressed_size as u32));
-    try!(writer.write_le_u32(file.uncompressed_size as u32));
+    static VERSION_NEEDED_OFFSET : u64 = 4;
+    let mut patch = io::MemWriter::new();
+    try!(patch.write_le_u16(file.version_needed));
+    try!(patch.write_le_u16(general_purpose_flags(file, false)));
+    try!(patch.write_le_u16(file.compression_method as u16));
+    let (msdos_time, msdos_date) = util::tm_to_msdos_datetime(file.last_modified_time);
+    try!(patch.write_le_u16(msdos_time));
+    try!(patch.write_le_u16(msdos_date));
+    try!(patch.write_le_u32(file.crc32));
+    try!(write_local_sizes(&mut patch, file));
+
+    if file.zip64
+    {
+        // The ZIP64 extra field comes first, right after the name
+        let name_length = file.file_name.as_bytes().len() as u64;
+        let extra_field_length = file.data_start - file.header_start - 30 - name_length;
+        try!(patch.write_le_u16(name_length as u16));
+        try!(patch.write_le_u16(extra_field_length as u16));
+        try!(patch.write(file.file_name.as_bytes()));
+        try!(patch.write_le_u16(0x0001));
+        try!(patch.write_le_u16(16));
+        try!(patch.write_le_u64(file.uncompressed_size));
+        try!(patch.write_le_u64(file.compressed_size));
+    }
+
+    try!(writer.seek((file.header_start + VERSION_NEEDED_OFFSET) as i64, io::SeekSet));
+    writer.write(patch.get_ref())
+}
+
+/// Write the data descriptor of the file, holding its checksum and sizes, optionally preceded by its signature.
+///
+/// The sizes take 8 bytes when the local header has a ZIP64 extra field, and 4 bytes otherwise.
+pub fn write_data_descriptor<T: Writer>(writer: &mut T, file: &ZipFile, signature: bool) -> IoResult<()>
+{
+    let mut descriptor = io::MemWriter::new();
+    if signature { try!(descriptor.write_le_u32(spec::DATA_DESCRIPTOR_SIGNATURE)); }
+    try!(descriptor.write_le_u32(file.crc32));
+    if file.zip64
+    {
+        try!(descriptor.write_le_u64(file.compressed_size));
+        try!(descriptor.write_le_u64(file.uncompressed_size));
+    }
+    else
+    {
+        try!(write_local_sizes(&mut descriptor, file));
+    }
+    writer.write(descriptor.get_ref())
+}
+
+fn write_local_sizes<T: Writer>(writer: &mut T, file: &ZipFile) -> IoResult<()>
+{
+    if file.zip64
+    {
+        try!(writer.write_le_u32(ZIP64_LIMIT as u32));
+        try!(writer.write_le_u32(ZIP64_LIMIT as u32));
+    }
+    else
+    {
+        // Without a ZIP64 extra field, a size that does not fit is marked as unknown
+        try!(writer.write_le_u32(::std::cmp::min(file.compressed_size, ZIP64_LIMIT) as u32));
+        try!(writer.write_le_u32(::std::cmp::min(file.uncompressed_size, ZIP64_LIMIT) as u32));
+    }
     Ok(())
 }
 
 pub fn write_central_directory_header<T: Writer>(writer: &mut T, file: &ZipFile) -> IoResult<()>
 {
+    let extra_field = try!(build_extra_field(file, true));
+    try!(check_extra_field_length(extra_field.as_slice()));
+
     try!(writer.write_le_u32(spec::CENTRAL_DIRECTORY_HEADER_SIGNATURE));
-    try!(writer.write_le_u16(0x14FF));
-    try!(writer.write_le_u16(20));
-    let flag = if !file.file_name.is_ascii() { 1u16 << 11 } else { 0 };
-    try!(writer.write_le_u16(flag));
+    try!(writer.write_le_u16(file.version_made_by));
+    try!(writer.write_le_u16(file.version_needed));
+    try!(writer.write_le_u16(general_purpose_flags(file, true)));
     try!(writer.write_le_u16(file.compression_method as u16));
-    try!(writer.write_le_u16(util::tm_to_msdos_time(file.last_modified_time)));
-    try!(writer.write_le_u16(util::tm_to_msdos_date(file.last_modified_time)));
+    let (msdos_time, msdos_date) = util::tm_to_msdos_datetime(file.last_modified_time);
+    try!(writer.write_le_u16(msdos_time));
+    try!(writer.write_le_u16(msdos_date));
     try!(writer.write_le_u32(file.crc32));
-    try!(writer.write_le_u32(file.compressed_size as u32));
-    try!(writer.write_le_u32(file.uncompressed_size as u32));
+    let zip64 = file.zip64 || needs_zip64(file);
+    try!(writer.write_le_u32(if zip64 { ZIP64_LIMIT as u32 } else { file.compressed_size as u32 }));
+    try!(writer.write_le_u32(if zip64 { ZIP64_LIMIT as u32 } else { file.uncompressed_size as u32 }));
     try!(writer.write_le_u16(file.file_name.as_bytes().len() as u16));
-    let extra_field = try!(build_extra_field(file));
     try!(writer.write_le_u16(extra_field.len() as u16));
+    try!(writer.write_le_u16(file.file_comment.as_bytes().len() as u16));
     try!(writer.write_le_u16(0));
-    try!(writer.write_le_u16(0));
-    try!(writer.write_le_u16(0));
-    try!(writer.write_le_u32(0));
-    try!(writer.write_le_u32(file.header_start as u32));
+    try!(writer.write_le_u16(file.internal_attributes));
+    try!(writer.write_le_u32(file.external_attributes));
+    try!(writer.write_le_u32(if zip64 { ZIP64_LIMIT as u32 } else { file.header_start as u32 }));
     try!(writer.write(file.file_name.as_bytes()));
     try!(writer.write(extra_field.as_slice()));
+    try!(writer.write(file.file_comment.as_bytes()));
 
     Ok(())
 }
 
-fn build_extra_field(_file: &ZipFile) -> IoResult<Vec<u8>>
+fn general_purpose_flags(file: &ZipFile, central: bool) -> u16
+{
+    // Bits that are derived from other fields are not taken from the retained flags
+    static DERIVED_BITS : u16 = 1 | (1 << 3) | (1 << 11);
+    let mut flags = file.flags & !DERIVED_BITS;
+    if file.encrypted { flags |= 1; }
+    if file.using_data_descriptor { flags |= 1 << 3; }
+    if !file.file_name.is_ascii() || (central && !file.file_comment.is_ascii()) { flags |= 1 << 11; }
+    flags
+}
+
+fn build_extra_field(file: &ZipFile, central: bool) -> IoResult<Vec<u8>>
 {
-    let writer = io::MemWriter::new();
-    // Future work
+    let mut writer = io::MemWriter::new();
+
+    // All fields are replaced by the sentinel, so all of them are present
+    if central && (file.zip64 || needs_zip64(file))
+    {
+        try!(writer.write_le_u16(0x0001));
+        try!(writer.write_le_u16(24));
+        try!(writer.write_le_u64(file.uncompressed_size));
+        try!(writer.write_le_u64(file.compressed_size));
+        try!(writer.write_le_u64(file.header_start));
+    }
+    else if !central && file.zip64
+    {
+        try!(writer.write_le_u16(0x0001));
+        try!(writer.write_le_u16(16));
+        try!(writer.write_le_u64(file.uncompressed_size));
+        try!(writer.write_le_u64(file.compressed_size));
+    }
+
+    if file.last_access_time.is_some() || file.creation_time.is_some()
+    {
+        try!(write_extended_timestamp(&mut writer, file, central));
+    }
+    try!(writer.write(file.extra_field.as_slice()));
+
     Ok(writer.unwrap())
 }
+
+/// Write the extended timestamp (0x5455) block of the file.
+///
+/// The flags list every time that is present, but like Info-ZIP, the central block only holds the
+/// modification time. The access and creation times are only in the local block.
+fn write_extended_timestamp<T: Writer>(writer: &mut T, file: &ZipFile, central: bool) -> IoResult<()>
+{
+    let mut flags = 1u8;
+    let mut times = vec![file.last_modified_time];
+    match file.last_access_time
+    {
+        Some(tm) => { flags |= 2; times.push(tm); },
+        None => {},
+    }
+    match file.creation_time
+    {
+        Some(tm) => { flags |= 4; times.push(tm); },
+        None => {},
+    }
+    if central { times.truncate(1); }
+
+    try!(writer.write_le_u16(0x5455));
+    try!(writer.write_le_u16(1 + 4 * times.len() as u16));
+    try!(writer.write_u8(flags));
+    for tm in times.iter()
+    {
+        let seconds = tm.to_timespec().sec;
+        if seconds < ::std::i32::MIN as i64 || seconds > ::std::i32::MAX as i64
+        {
+            return Err(IoError { kind: io::InvalidInput, desc: "Time does not fit in the extended timestamp", detail: None })
+        }
+        try!(writer.write_le_i32(seconds as i32));
+    }
+    Ok(())
+}
@@ -0,0 +1,89 @@
+//! Decryption of files encrypted with the traditional PKWARE encryption, also known as ZipCrypto.
+//!
+//! This encryption is weak, and only supported for reading existing ZIP files.
+
+use std::io;
+use std::io::{IoResult, IoError};
+use crc32;
+
+/// Length of the encryption header that precedes the encrypted data.
+pub static ENCRYPTION_HEADER_SIZE : u64 = 12;
+
+struct ZipCryptoKeys
+{
+    key0: u32,
+    key1: u32,
+    key2: u32,
+}
+
+impl ZipCryptoKeys
+{
+    fn new(password: &[u8]) -> ZipCryptoKeys
+    {
+        let mut keys = ZipCryptoKeys { key0: 0x12345678, key1: 0x23456789, key2: 0x34567890, };
+        for &byte in password.iter()
+        {
+            keys.update(byte);
+        }
+        keys
+    }
+
+    fn update(&mut self, plain: u8)
+    {
+        self.key0 = crc32::update_raw_byte(self.key0, plain);
+        self.key1 = (self.key1 + (self.key0 & 0xFF)) * 134775813 + 1;
+        self.key2 = crc32::update_raw_byte(self.key2, (self.key1 >> 24) as u8);
+    }
+
+    fn decrypt(&mut self, cipher: u8) -> u8
+    {
+        let temp = (self.key2 | 2) as u16;
+        let plain = cipher ^ ((temp * (temp ^ 1)) >> 8) as u8;
+        self.update(plain);
+        plain
+    }
+}
+
+/// Reader that decrypts the data of a file, starting with its encryption header.
+pub struct ZipCryptoReader<R>
+{
+    inner: R,
+    keys: ZipCryptoKeys,
+}
+
+impl<R: Reader> ZipCryptoReader<R>
+{
+    /// Read and decrypt the encryption header, checking the password with its last byte.
+    ///
+    /// The last byte must equal `check_byte`: the high byte of the checksum, or of the MS-DOS
+    /// modification time when the file has a data descriptor. Fails with `InvalidInput` when it
+    /// does not, which almost always means the password is wrong.
+    pub fn new(mut inner: R, password: &[u8], check_byte: u8) -> IoResult<ZipCryptoReader<R>>
+    {
+        let mut keys = ZipCryptoKeys::new(password);
+        let header = try!(inner.read_exact(ENCRYPTION_HEADER_SIZE as uint));
+        let mut last = 0;
+        for &byte in header.iter()
+        {
+            last = keys.decrypt(byte);
+        }
+        if last != check_byte
+        {
+            return Err(IoError { kind: io::InvalidInput, desc: "Invalid password", detail: None })
+        }
+        Ok(ZipCryptoReader { inner: inner, keys: keys, })
+    }
+}
+
+impl<R: Reader> Reader for ZipCryptoReader<R>
+{
+    fn read(&mut self, buf: &mut [u8]) -> IoResult<uint>
+    {
+        let count = try!(self.inner.read(buf));
+        for byte in buf.slice_to_mut(count).iter_mut()
+        {
+            *byte = self.keys.decrypt(*byte);
+        }
+        Ok(count)
+    }
+}